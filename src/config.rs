@@ -0,0 +1,86 @@
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use helvar_cgi::helvarnet::transport::TransportKind;
+use helvar_cgi::helvarnet::address;
+
+/// How to reach a router, e.g. "10.254.1.1" or "udp:10.254.1.1".
+/// TCP is used unless another transport is given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterConfig
+{
+    pub addr: Ipv4Addr,
+    pub transport: TransportKind
+}
+
+impl FromStr for RouterConfig
+{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let s = s.trim();
+        let (transport, addr) = match s.find(':') {
+            Some(split) => (TransportKind::from_str(&s[..split])?,
+                            &s[split+1..]),
+            None => (TransportKind::Tcp, s)
+        };
+        let addr = Ipv4Addr::from_str(addr)
+            .map_err(|e| format!("Invalid router address \"{}\": {}",
+                                 addr, e))?;
+        Ok(RouterConfig{addr, transport})
+    }
+}
+
+/// Parse a list of routers separated by commas or white space
+pub fn parse_router_list(s: &str) -> Result<Vec<RouterConfig>, String>
+{
+    let routers = s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|r| !r.is_empty())
+        .map(RouterConfig::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+    if routers.is_empty() {
+        return Err("No router address given".to_string());
+    }
+    Ok(routers)
+}
+
+/// Parse a list of group numbers separated by commas or white space
+pub fn parse_group_list(s: &str) -> Result<Vec<u16>, String>
+{
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|g| !g.is_empty())
+        .map(|g| address::parse_group(g)
+             .map_err(|e| format!("Invalid group \"{}\": {}", g, e)))
+        .collect()
+}
+
+#[test]
+fn test_router_config()
+{
+    let conf = RouterConfig::from_str("10.254.1.1").unwrap();
+    assert_eq!(conf.addr, Ipv4Addr::new(10,254,1,1));
+    assert_eq!(conf.transport, TransportKind::Tcp);
+    let conf = RouterConfig::from_str("udp:10.254.1.2").unwrap();
+    assert_eq!(conf.addr, Ipv4Addr::new(10,254,1,2));
+    assert_eq!(conf.transport, TransportKind::Udp);
+    assert!(RouterConfig::from_str("tcp:10.254.1").is_err());
+    assert!(RouterConfig::from_str("ftp:10.254.1.1").is_err());
+}
+
+#[test]
+fn test_router_list()
+{
+    let routers = parse_router_list("10.254.1.1, udp:10.254.1.2\n10.254.2.1")
+        .unwrap();
+    assert_eq!(routers.len(), 3);
+    assert_eq!(routers[1].transport, TransportKind::Udp);
+    assert_eq!(routers[2].addr, Ipv4Addr::new(10,254,2,1));
+    assert!(parse_router_list(" , ").is_err());
+}
+
+#[test]
+fn test_group_list()
+{
+    assert_eq!(parse_group_list("1, 17 300").unwrap(), vec![1, 17, 300]);
+    assert!(parse_group_list("").unwrap().is_empty());
+    assert!(parse_group_list("1,0").is_err());
+}
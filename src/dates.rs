@@ -0,0 +1,180 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64
+{
+    let y = if month <= 2 {year - 1} else {year};
+    let era = if y >= 0 {y} else {y - 399} / 400;
+    let yoe = y - era * 400;
+    let m = i64::from(month);
+    let doy = (153 * (if m > 2 {m - 3} else {m + 9}) + 2) / 5
+        + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Year, month and day of a number of days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, u32, u32)
+{
+    let z = days + 719468;
+    let era = if z >= 0 {z} else {z - 146096} / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 {mp + 3} else {mp - 9} as u32;
+    let year = yoe + era * 400 + if month <= 2 {1} else {0};
+    (year, month, day)
+}
+
+/// Parse a date like "2020-06-30" into days since 1970-01-01
+pub fn parse_date(s: &str) -> Result<i64, String>
+{
+    let err = || format!("Invalid date \"{}\", expected YYYY-MM-DD", s);
+    let parts: Vec<&str> = s.trim().split('-').collect();
+    if parts.len() != 3 {
+        return Err(err());
+    }
+    let year: i64 = parts[0].parse().map_err(|_| err())?;
+    let month: u32 = parts[1].parse().map_err(|_| err())?;
+    let day: u32 = parts[2].parse().map_err(|_| err())?;
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(err());
+    }
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) {
+        return Err(err());
+    }
+    Ok(days)
+}
+
+/// Format the local date as "2020-06-30"
+pub fn format_date(time: SystemTime) -> String
+{
+    let (year, month, day) = local_date(time);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Format the local time as "2020-06-30 14:05"
+pub fn format_time(time: SystemTime) -> String
+{
+    let tm = local_tm(time);
+    format!("{} {:02}:{:02}", format_date(time), tm.tm_hour, tm.tm_min)
+}
+
+/// Day of the week, 0 for Monday, of a number of days since 1970-01-01
+pub fn weekday(days: i64) -> u32
+{
+    // 1970-01-01 was a Thursday
+    (days + 3).rem_euclid(7) as u32
+}
+
+fn local_tm(time: SystemTime) -> libc::tm
+{
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs())
+        .unwrap_or(0) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::localtime_r(&secs, &mut tm);
+    }
+    tm
+}
+
+/// The date in the local time zone
+pub fn local_date(time: SystemTime) -> (i64, u32, u32)
+{
+    let tm = local_tm(time);
+    (i64::from(tm.tm_year) + 1900, (tm.tm_mon + 1) as u32, tm.tm_mday as u32)
+}
+
+/// Days since 1970-01-01 of the date in the local time zone
+pub fn local_day(time: SystemTime) -> i64
+{
+    let (year, month, day) = local_date(time);
+    days_from_civil(year, month, day)
+}
+
+/// A time of day on a date in the local time zone. None if the time
+/// doesn't exist, e.g. when skipped by daylight saving.
+pub fn local_time(year: i64, month: u32, day: u32, hour: u32, minute: u32)
+                  -> Option<SystemTime>
+{
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = (year - 1900) as libc::c_int;
+    tm.tm_mon = month as libc::c_int - 1;
+    tm.tm_mday = day as libc::c_int;
+    tm.tm_hour = hour as libc::c_int;
+    tm.tm_min = minute as libc::c_int;
+    tm.tm_isdst = -1;
+    let secs = unsafe { libc::mktime(&mut tm) };
+    if secs < 0 || tm.tm_hour != hour as libc::c_int {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Start of a day, given as days since 1970-01-01, in the local time
+/// zone. Where daylight saving skips midnight the day starts at 01:00.
+pub fn local_midnight(days: i64) -> SystemTime
+{
+    let (year, month, day) = civil_from_days(days);
+    local_time(year, month, day, 0, 0)
+        .or_else(|| local_time(year, month, day, 1, 0))
+        .unwrap_or(UNIX_EPOCH + Duration::from_secs(days.max(0) as u64
+                                                    * SECS_PER_DAY))
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                            "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Parse a time given by a router, like "10:00:00 01-Jun-2020". The
+/// router clock is taken to be in the local time zone.
+pub fn parse_router_time(s: &str) -> Option<SystemTime>
+{
+    let mut parts = s.trim().splitn(2, ' ');
+    let time: Vec<u32> = parts.next()?.split(':')
+        .map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    let date: Vec<&str> = parts.next()?.trim().split('-').collect();
+    if time.len() != 3 || date.len() != 3 || time[2] > 59 {
+        return None;
+    }
+    let day: u32 = date[0].parse().ok()?;
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(date[1]))?
+        as u32 + 1;
+    let year: i64 = date[2].parse().ok()?;
+    if civil_from_days(days_from_civil(year, month, day)) != (year, month, day)
+        || time[0] > 23 || time[1] > 59
+    {
+        return None;
+    }
+    local_time(year, month, day, time[0], time[1])
+        .map(|t| t + Duration::from_secs(u64::from(time[2])))
+}
+
+#[test]
+fn test_dates()
+{
+    let days = parse_date("2020-06-30").unwrap();
+    assert_eq!(days, 18443);
+    assert_eq!(format_date(local_midnight(days)), "2020-06-30");
+    assert_eq!(local_day(local_midnight(days)), days);
+    assert_eq!(format_time(local_time(2020, 6, 30, 1, 5).unwrap()),
+               "2020-06-30 01:05");
+    assert_eq!(format_date(local_midnight(parse_date("2000-02-29").unwrap())),
+               "2000-02-29");
+    assert!(parse_date("2019-02-29").is_err());
+    assert!(parse_date("2020-13-01").is_err());
+    assert!(parse_date("2020-06").is_err());
+    // A Tuesday
+    assert_eq!(weekday(days_from_civil(2020, 6, 30)), 1);
+
+    let t = parse_router_time("10:00:07 01-Jun-2020").unwrap();
+    assert_eq!(t, local_time(2020, 6, 1, 10, 0).unwrap()
+               + Duration::from_secs(7));
+    assert!(parse_router_time("10:00:00 31-Jun-2020").is_none());
+    assert!(parse_router_time("25:00:00 01-Jun-2020").is_none());
+    assert!(parse_router_time("10:00 01-Jun-2020").is_none());
+}
@@ -27,8 +27,8 @@ pub fn decode_name_value_pair(mut block: Bytes)
 #[test]
 fn test_decode_name_value_pair_11()
 {
-    let mut block = Bytes::from_static(&[2u8,3,1,2,6,5,4]);
-    let (name, value, b) = decode_name_value_pair(block);
+    let block = Bytes::from_static(&[2u8,3,1,2,6,5,4]);
+    let (name, value, _) = decode_name_value_pair(block);
     assert_eq!(name, Bytes::from_static(&[1,2]));
     assert_eq!(value, Bytes::from_static(&[6,5,4]));
 }
@@ -36,8 +36,8 @@ fn test_decode_name_value_pair_11()
 #[test]
 fn test_decode_name_value_pair_41()
 {
-    let mut block = Bytes::from_static(&[0x80u8,0,0,3, 3, 1,2,3, 6,5,4]);
-    let (name, value, b) = decode_name_value_pair(block);
+    let block = Bytes::from_static(&[0x80u8,0,0,3, 3, 1,2,3, 6,5,4]);
+    let (name, value, _) = decode_name_value_pair(block);
     assert_eq!(name, Bytes::from_static(&[1,2,3]));
     assert_eq!(value, Bytes::from_static(&[6,5,4]));
 }
@@ -45,8 +45,8 @@ fn test_decode_name_value_pair_41()
 #[test]
 fn test_decode_name_value_pair_14()
 {
-    let mut block = Bytes::from_static(&[3u8,0x80, 0,0,3, 1,2,3, 6,5,4]);
-    let (name, value, b) = decode_name_value_pair(block);
+    let block = Bytes::from_static(&[3u8,0x80, 0,0,3, 1,2,3, 6,5,4]);
+    let (name, value, _) = decode_name_value_pair(block);
     assert_eq!(name, Bytes::from_static(&[1,2,3]));
     assert_eq!(value, Bytes::from_static(&[6,5,4]));
 }
@@ -54,9 +54,9 @@ fn test_decode_name_value_pair_14()
 #[test]
 fn test_decode_name_value_pair_44()
 {
-    let mut block = Bytes::from_static(&[0x80u8, 0,0, 3,0x80, 0,0,3, 1,2,3,
-                                         6,5,4]);
-    let (name, value, b) = decode_name_value_pair(block);
+    let block = Bytes::from_static(&[0x80u8, 0,0, 3,0x80, 0,0,3, 1,2,3,
+                                     6,5,4]);
+    let (name, value, _) = decode_name_value_pair(block);
     assert_eq!(name, Bytes::from_static(&[1,2,3]));
     assert_eq!(value, Bytes::from_static(&[6,5,4]));
 }
@@ -1,8 +1,12 @@
 use std::collections::HashMap;
 use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
+use tokio::io::Error;
 use tokio::stream::StreamExt;
+use tokio::sync::watch;
 use super::records::ServerRecord;
 use super::records::AppRecord;
 use super::records::EndRequest;
@@ -13,77 +17,223 @@ use super::request::{Request, RequestHandler};
 use super::input_stream::RecordInputStream;
 use super::record_output::RecordOutput;
 
+/// Largest amount of content that fits in a single record
+const MAX_CONTENT_LEN: usize = 0xffff;
+
+/// Counters that can be shared by the decoders of all connections
+#[derive(Debug, Default)]
+pub struct DecoderStats
+{
+    write_errors: AtomicU64
+}
+
+impl DecoderStats
+{
+    /// Records that couldn't be written to the web server
+    pub fn write_errors(&self) -> u64
+    {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+}
 
 pub struct Decoder
 {
-    requests: HashMap<u16, Request>
+    requests: HashMap<u16, Request>,
+    shutdown: Option<watch::Receiver<bool>>,
+    stats: Arc<DecoderStats>
 }
 
+impl Default for Decoder
+{
+    fn default() -> Decoder
+    {
+        Decoder::new()
+    }
+}
 
 impl Decoder
 {
     pub fn new() -> Decoder
     {
-        Decoder{requests: HashMap::<u16,Request>::new()}
+        Decoder{requests: HashMap::<u16,Request>::new(),
+                shutdown: None,
+                stats: Arc::new(DecoderStats::default())}
+    }
+
+    /// Count into `stats` instead of counters of this decoder only
+    pub fn set_stats(&mut self, stats: Arc<DecoderStats>)
+    {
+        self.stats = stats;
+    }
+
+    pub fn stats(&self) -> &Arc<DecoderStats>
+    {
+        &self.stats
+    }
+
+    /// Stop reading new requests once `shutdown` becomes true. Requests
+    /// already in progress are completed first.
+    pub fn set_shutdown(&mut self, shutdown: watch::Receiver<bool>)
+    {
+        self.shutdown = Some(shutdown);
     }
 
-    async fn write_error<O>(output: &mut RecordOutput<O>, msg: &str)
+    /// Every write goes through here, so all write errors are counted
+    async fn write_record<O>(&self, output: &mut RecordOutput<O>,
+                             rec: AppRecord, request_id: u16)
+                             -> Result<(), Error>
+        where O: AsyncWrite + Unpin + Send + 'static,
+    {
+        let res = match rec.encode(request_id) {
+            Ok(rec) => output.write(&rec).await,
+            Err(e) => Err(Error::new(std::io::ErrorKind::InvalidData,
+                                     e.description))
+        };
+        if res.is_err() {
+            self.stats.write_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// Write a reply as one or more StdOut records
+    async fn write_stdout<O>(&self, output: &mut RecordOutput<O>,
+                             mut data: Bytes, request_id: u16)
+                             -> Result<(), Error>
+        where O: AsyncWrite + Unpin + Send + 'static,
+    {
+        while !data.is_empty() {
+            let chunk = data.split_to(data.len().min(MAX_CONTENT_LEN));
+            self.write_record(output, AppRecord::StdOut(chunk),
+                               request_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn end_request<O>(&self, output: &mut RecordOutput<O>,
+                            request_id: u16)
+                            -> Result<(), Error>
+        where O: AsyncWrite + Unpin + Send + 'static,
+    {
+        let reply = AppRecord::EndRequest(
+            EndRequest{
+                app_status: 0,
+                protocol_status: defs::FCGI_REQUEST_COMPLETE
+            });
+        self.write_record(output, reply, request_id).await
+    }
+
+    async fn write_error<O>(&self, output: &mut RecordOutput<O>, msg: &str,
+                            request_id: u16)
+                            -> Result<(), Error>
         where O: AsyncWrite + Unpin + Send + 'static,
     {
         let out = AppRecord::StdErr(
             Bytes::from(msg.to_string())
         );
-        output.write(&out.encode(0).unwrap()).await.unwrap();
+        self.write_record(output, out, request_id).await
     }
 
-    async fn error_reply<O>(output: &mut RecordOutput<O>, 
-                      err: Box<dyn std::error::Error + Send>, 
-                      request_id: u16)
+    async fn error_reply<O>(&self, output: &mut RecordOutput<O>,
+                            err: Box<dyn std::error::Error + Send>,
+                            request_id: u16)
+                            -> Result<(), Error>
         where O: AsyncWrite + Unpin + Send + 'static,
     {
         let out = AppRecord::StdOut({
             let reply = "Status: 500 Internal error\r\n\r\n";
             Bytes::from(reply)
         });
-        output.write(&out.encode(request_id).unwrap()).await.unwrap();
+        self.write_record(output, out, request_id).await?;
 
-        Self::write_error(output,&format!("App failed with error: {}",err)).await;
+        self.write_error(output,&format!("App failed with error: {}",err),
+                          request_id).await?;
 
-        let reply = AppRecord::EndRequest(
-            EndRequest{
-                app_status: 0,
-                protocol_status: defs::FCGI_REQUEST_COMPLETE
-            });
-        output.write(&reply.encode(request_id).unwrap()).await.unwrap();
-        
+        self.end_request(output, request_id).await
     }
-    
+
     pub async fn run<I,O>(&mut self,
                      mut input_stream: RecordInputStream<I>,
                      mut output: RecordOutput<O>,
                      handler: &mut dyn RequestHandler
-    ) where I: AsyncRead + Unpin + Send + 'static, 
+    ) where I: AsyncRead + Unpin + Send + 'static,
             O: AsyncWrite + Unpin + Send + 'static
     {
-        while let Some(rec) = input_stream.next().await {
+        if let Err(e) = self.process(&mut input_stream, &mut output,
+                                     handler).await {
+            // The web server went away, there's no one left to reply to
+            eprintln!("Closing FastCGI connection: {}", e);
+        } else if input_stream.timed_out() {
+            self.abort_requests(&mut output).await;
+        }
+        self.requests.clear();
+    }
+
+    /// End all outstanding requests. Write errors are ignored since
+    /// the connection is about to be closed anyway.
+    async fn abort_requests<O>(&mut self, output: &mut RecordOutput<O>)
+        where O: AsyncWrite + Unpin + Send + 'static,
+    {
+        let request_ids: Vec<u16> = self.requests.drain()
+            .map(|(request_id, _)| request_id).collect();
+        for request_id in request_ids {
+            let reply = AppRecord::EndRequest(
+                EndRequest{
+                    app_status: 1,
+                    protocol_status: defs::FCGI_REQUEST_COMPLETE
+                });
+            if self.write_record(output, reply, request_id).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn process<I,O>(&mut self,
+                          input_stream: &mut RecordInputStream<I>,
+                          output: &mut RecordOutput<O>,
+                          handler: &mut dyn RequestHandler
+    ) -> Result<(), Error>
+        where I: AsyncRead + Unpin + Send + 'static,
+              O: AsyncWrite + Unpin + Send + 'static
+    {
+        loop {
+            let rec = match &mut self.shutdown {
+                Some(shutdown) if self.requests.is_empty() => {
+                    if *shutdown.borrow() {
+                        break;
+                    }
+                    tokio::select! {
+                        rec = input_stream.next() => rec,
+                        changed = shutdown.recv() => {
+                            if changed.is_none() {
+                                break;
+                            }
+                            continue;
+                        }
+                    }
+                },
+                _ => input_stream.next().await
+            };
+            let rec = match rec {
+                Some(rec) => rec,
+                None => break
+            };
             if rec.request_id == 0 {
                 let unknown = AppRecord::UnknownType(rec.rec_type);
-                output.write(&unknown.encode(rec.request_id).unwrap()).await.unwrap_or(());
+                self.write_record(output, unknown, rec.request_id).await?;
             } else {
                 match ServerRecord::decode(&rec) {
                     Ok(ServerRecord::BeginRequest(begin)) => {
-                        //println!("Begin: {:?}", begin);
                         if begin.role !=defs::FCGI_RESPONDER {
                             let end_rec = EndRequest{
                                 protocol_status: defs::FCGI_UNKNOWN_ROLE,
                                 app_status: 0
                             };
                             let end = AppRecord::EndRequest(end_rec);
-                            output.write(&end.encode(rec.request_id).unwrap())
-                                .await.unwrap_or(());
+                            self.write_record(output, end,
+                                               rec.request_id).await?;
                         } else {
                             let params = BTreeMap::new();
-                            self.requests.insert(rec.request_id, 
+                            self.requests.insert(rec.request_id,
                                                  Request{
                                                      params,
                                                      stdin: None,
@@ -93,14 +243,14 @@ impl Decoder
                         }
                     },
                     Ok(ServerRecord::Params(pairs)) => {
-                        if let Some(request) = 
-                            self.requests.get_mut(&rec.request_id) 
+                        if let Some(request) =
+                            self.requests.get_mut(&rec.request_id)
                         {
                             if pairs.is_empty() {
-                                if let Some(len) = 
+                                if let Some(len) =
                                     request.params.get("CONTENT_LENGTH")
-                                    .and_then(|s| { 
-                                        usize::from_str_radix(s, 10)
+                                    .and_then(|s| {
+                                        s.parse::<usize>()
                                             .map_or(None , |l| {
                                                 if l > 0 {Some(l)} else {None}
                                             })
@@ -118,8 +268,8 @@ impl Decoder
                         }
                     },
                     Ok(ServerRecord::StdIn(data)) => {
-                        if let Some(request) = 
-                            self.requests.get_mut(&rec.request_id) 
+                        if let Some(request) =
+                            self.requests.get_mut(&rec.request_id)
                         {
                             let len = data.len();
                             if len > 0 {
@@ -135,47 +285,37 @@ impl Decoder
                             } else {
                                 request.request_done = true;
                             }
-                        }   
+                        }
                     },
                     Ok(ServerRecord::Abort) => {
-                        let reply = AppRecord::EndRequest(
-                            EndRequest{
-                                app_status: 0,
-                                protocol_status: defs::FCGI_REQUEST_COMPLETE
-                            });
-                        output.write(&reply.encode(rec.request_id).unwrap()).await.unwrap();
+                        self.requests.remove(&rec.request_id);
+                        self.end_request(output, rec.request_id).await?;
                     },
-                    Ok(r) => {
-                        println!("Other: {:?}", r);
+                    Ok(_) => {
                         let unknown = AppRecord::UnknownType(rec.rec_type);
-                        output.write(&unknown.encode(rec.request_id).unwrap()).await.unwrap_or(());
-
+                        self.write_record(output, unknown,
+                                           rec.request_id).await?;
                     }
                     Err(e) => {
                         eprintln!("Failed to decode record: {}", e);
                     }
-                    
+
                 }
                 match self.requests.get_mut(&rec.request_id) {
                     Some(req) if req.request_done =>
                     {
-                        match handler.handle(req).await {
+                        let res = handler.handle(req).await;
+                        self.requests.remove(&rec.request_id);
+                        match res {
                             Ok(reply) => {
-                                let out = AppRecord::StdOut(
-                                    Bytes::from(reply)
-                                );
-                                output.write(&out.encode(rec.request_id).unwrap()).await.unwrap();
-                                let reply = AppRecord::EndRequest(
-                                    EndRequest{
-                                        app_status: 0,
-                                        protocol_status: defs::FCGI_REQUEST_COMPLETE
-                                    });
-                                output.write(&reply.encode(rec.request_id).unwrap()).await.unwrap_or(());
-                                //println!("Request done");
-                                self.requests.remove(&rec.request_id);
+                                self.write_stdout(output, Bytes::from(reply),
+                                                   rec.request_id).await?;
+                                self.end_request(output,
+                                                  rec.request_id).await?;
                             },
                             Err(e) => {
-                                Self::error_reply(&mut output, e, rec.request_id).await;
+                                self.error_reply(output, e,
+                                                  rec.request_id).await?;
                             }
                         }
                     },
@@ -183,7 +323,85 @@ impl Decoder
                 }
             }
         }
-        //println!("Connection closed");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use tokio::runtime::Runtime;
+#[cfg(test)]
+use tokio::sync::Mutex;
+
+#[cfg(test)]
+struct ClosedOutput;
+
+#[cfg(test)]
+impl AsyncWrite for ClosedOutput
+{
+    fn poll_write(self: std::pin::Pin<&mut Self>,
+                  _cx: &mut std::task::Context<'_>, _buf: &[u8])
+                  -> std::task::Poll<Result<usize, Error>>
+    {
+        std::task::Poll::Ready(Err(Error::from(
+            std::io::ErrorKind::BrokenPipe)))
+    }
+    fn poll_flush(self: std::pin::Pin<&mut Self>,
+                  _cx: &mut std::task::Context<'_>)
+                  -> std::task::Poll<Result<(), Error>>
+    {
+        std::task::Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: std::pin::Pin<&mut Self>,
+                     _cx: &mut std::task::Context<'_>)
+                     -> std::task::Poll<Result<(), Error>>
+    {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// Counts the requests handled
+#[cfg(test)]
+struct EchoHandler(usize);
+
+#[cfg(test)]
+#[async_trait]
+impl RequestHandler for EchoHandler
+{
+    async fn handle(&mut self, _req: &Request)
+                    -> Result<String, Box<dyn std::error::Error + Send>>
+    {
+        self.0 += 1;
+        Ok("Content-type: text/plain\r\n\r\nHello".to_string())
     }
 }
 
+#[test]
+fn test_decoder_write_error()
+{
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        // Two requests, each a begin request followed by empty params
+        // and stdin records
+        let input: &[u8] = &[1u8, 1, 0, 1, 0, 8, 0, 0,
+                             0, 1, 0, 0, 0, 0, 0, 0,
+                             1, 4, 0, 1, 0, 0, 0, 0,
+                             1, 5, 0, 1, 0, 0, 0, 0,
+                             1, 1, 0, 2, 0, 8, 0, 0,
+                             0, 1, 0, 0, 0, 0, 0, 0,
+                             1, 4, 0, 2, 0, 0, 0, 0,
+                             1, 5, 0, 2, 0, 0, 0, 0];
+        let input = RecordInputStream::new(Arc::new(Mutex::new(input)));
+        let output = RecordOutput::new(Arc::new(Mutex::new(ClosedOutput)));
+        let mut decoder = Decoder::new();
+        let stats = Arc::new(DecoderStats::default());
+        decoder.set_stats(stats.clone());
+        let mut handler = EchoHandler(0);
+        tokio::time::timeout(std::time::Duration::from_secs(5),
+                             decoder.run(input, output, &mut handler))
+            .await.expect("Decoder kept running after a write error");
+        // The connection is given up on the first failed write, so the
+        // second request is never handled
+        assert_eq!(stats.write_errors(), 1);
+        assert_eq!(handler.0, 1);
+    });
+}
@@ -12,7 +12,6 @@ pub const FCGI_HEADER_LEN: u16 = 8;
 pub const FCGI_VERSION_1:u8 = 1;
 
 /// Values for type component of FCGI_Header
-
 pub const FCGI_BEGIN_REQUEST: u8 = 1;
 pub const FCGI_ABORT_REQUEST: u8 = 2;
 pub const FCGI_END_REQUEST: u8 = 3;
@@ -43,8 +42,8 @@ pub const FCGI_OVERLOADED: u8 = 2;
 pub const FCGI_UNKNOWN_ROLE: u8 = 3;
 
 /// Variable names for FCGI_GET_VALUES / FCGI_GET_VALUES_RESULT records
-pub const FCGI_MAX_CONNS: &'static str = "FCGI_MAX_CONNS";
-pub const FCGI_MAX_REQS: &'static str = "FCGI_MAX_REQS";
-pub const FCGI_MPXS_CONNS: &'static str = "FCGI_MPXS_CONNS";
+pub const FCGI_MAX_CONNS: &str = "FCGI_MAX_CONNS";
+pub const FCGI_MAX_REQS: &str = "FCGI_MAX_REQS";
+pub const FCGI_MPXS_CONNS: &str = "FCGI_MPXS_CONNS";
 
 
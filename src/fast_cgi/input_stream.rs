@@ -10,6 +10,28 @@ use std::sync::Arc;
 use tokio::sync::{Mutex,OwnedMutexGuard};
 use std::ops::DerefMut;
 use std::future::Future;
+use std::time::Duration;
+use tokio::time::{self, Delay};
+
+/// Read timeouts for a FastCGI connection. A timeout of None waits forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts
+{
+    /// Time to wait for the first byte of the next record
+    pub idle: Option<Duration>,
+    /// Time to wait for the rest of a record header once it has started
+    pub header: Option<Duration>,
+    /// Time to wait for the content and padding of a record
+    pub body: Option<Duration>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase
+{
+    Idle,
+    Header,
+    Body
+}
 
 pub struct RecordInputStream<I>
     where I: AsyncRead + Unpin + Send
@@ -21,22 +43,84 @@ pub struct RecordInputStream<I>
     padding_left: usize,
     // The future returned by lock_owned() must be saved so the task
     // will be notified when locking succeeds
-    lock_future: Option<Pin<Box<dyn Future<Output = OwnedMutexGuard<I>> + Send>>>
+    lock_future: Option<Pin<Box<dyn Future<Output = OwnedMutexGuard<I>> + Send>>>,
+    timeouts: Timeouts,
+    // Phase the current deadline was started for
+    phase: Option<Phase>,
+    deadline: Option<Delay>,
+    timed_out: bool
 }
 
 impl<I> RecordInputStream<I>
     where I: AsyncRead + Unpin + Send
 {
     pub fn new(input: Arc<Mutex<I>>) -> RecordInputStream<I>
+    {
+        Self::with_timeouts(input, Timeouts::default())
+    }
+
+    pub fn with_timeouts(input: Arc<Mutex<I>>, timeouts: Timeouts)
+                         -> RecordInputStream<I>
     {
         RecordInputStream{input,
                           buffer: BytesMut::new(),
                           record: None,
                           content_left: 0,
                           padding_left: 0,
-                          lock_future: None
+                          lock_future: None,
+                          timeouts,
+                          phase: None,
+                          deadline: None,
+                          timed_out: false
         }
     }
+
+    /// True if the stream ended because a read timeout expired
+    pub fn timed_out(&self) -> bool
+    {
+        self.timed_out
+    }
+
+    fn current_phase(&self) -> Phase
+    {
+        if self.record.is_some() || self.padding_left > 0 {
+            Phase::Body
+        } else if !self.buffer.is_empty() {
+            Phase::Header
+        } else {
+            Phase::Idle
+        }
+    }
+
+    /// Called when no more input is available. Returns true if the
+    /// deadline for the current phase has passed.
+    fn poll_deadline(&mut self, cx: &mut Context) -> bool
+    {
+        let phase = self.current_phase();
+        if self.phase != Some(phase) {
+            self.phase = Some(phase);
+            let timeout = match phase {
+                Phase::Idle => self.timeouts.idle,
+                Phase::Header => self.timeouts.header,
+                Phase::Body => self.timeouts.body
+            };
+            self.deadline = timeout.map(time::delay_for);
+        }
+        if let Some(deadline) = &mut self.deadline {
+            if Pin::new(deadline).poll(cx).is_ready() {
+                self.timed_out = true;
+            }
+        }
+        self.timed_out
+    }
+
+    fn take_record(&mut self) -> Option<Record>
+    {
+        // Restart the deadline when waiting for the next record
+        self.phase = None;
+        self.deadline = None;
+        self.record.take()
+    }
 }
 
 impl<I> Stream for RecordInputStream<I>
@@ -48,16 +132,19 @@ impl<I> Stream for RecordInputStream<I>
         -> Poll<Option<Self::Item>>
     {
         let mutable = &mut self.get_mut();
+        if mutable.timed_out {
+            return Poll::Ready(None)
+        }
         loop {
             //println!("Buf: {:?}", mutable.buffer);
-            if mutable.buffer.len() > 0 {
+            if !mutable.buffer.is_empty() {
                 if let Some(record) = &mut mutable.record {
                     let copy =  mutable.content_left.min(mutable.buffer.len());
                     record.content_data.extend_from_slice(
                         &mutable.buffer.split_to(copy));
                     mutable.content_left -= copy;
                     if mutable.content_left == 0 {
-                        return Poll::Ready(mutable.record.take())
+                        return Poll::Ready(mutable.take_record())
                     }
                     continue;
                 } else if mutable.padding_left > 0 {
@@ -73,8 +160,8 @@ impl<I> Stream for RecordInputStream<I>
                     mutable.content_left = header.get_u16().into();
                     mutable.padding_left = header.get_u8().into();
                     mutable.record = Some(Record{version:ver,
-                                                 rec_type: rec_type,
-                                                 request_id: request_id,
+                                                 rec_type,
+                                                 request_id,
                                                  content_data: BytesMut::new()
                     });
                     continue;
@@ -87,13 +174,24 @@ impl<I> Stream for RecordInputStream<I>
             let mut guard = 
                 match mutable.lock_future.as_mut().unwrap().as_mut().poll(cx) 
             {
-                Poll::Pending => return Poll::Pending,
+                Poll::Pending => {
+                    if mutable.poll_deadline(cx) {
+                        return Poll::Ready(None)
+                    }
+                    return Poll::Pending
+                },
                 Poll::Ready(inp) => inp
             };
             mutable.lock_future = None;
             let pinned = Pin::new(guard.deref_mut());
             match pinned.poll_read_buf(cx, &mut mutable.buffer) {
-                Poll::Pending => return Poll::Pending,
+                Poll::Pending => {
+                    drop(guard);
+                    if mutable.poll_deadline(cx) {
+                        return Poll::Ready(None)
+                    }
+                    return Poll::Pending
+                },
                 Poll::Ready(Err(_)) => return Poll::Ready(None),
                 Poll::Ready(Ok(0)) => return Poll::Ready(None),
                     Poll::Ready(Ok(_)) => {
@@ -127,7 +225,7 @@ fn test_input_stream()
                                     0x00])),
             ];
         let stream = stream::iter(blocks);
-        let mut src = tokio::io::stream_reader(stream);
+        let src = tokio::io::stream_reader(stream);
         
         let framer = RecordInputStream::new(Arc::new(Mutex::new(Box::new(src))));
         let records : Vec<Record> = framer.collect().await;
@@ -152,12 +250,12 @@ fn test_input_stream_blocked()
                                     0x00])),
         ];
         let stream = stream::iter(blocks);
-        let mut src = tokio::io::stream_reader(stream);
+        let src = tokio::io::stream_reader(stream);
         let arc_src = Arc::new(Mutex::new(src));
         let task;
         let local_src = arc_src.clone();
         {
-            let locked_src = local_src.lock().await;
+            let _locked_src = local_src.lock().await;
             task = tokio::spawn(async move {
                 let framer = RecordInputStream::new(arc_src);
                 let records : Vec<Record> = framer.collect().await;
@@ -167,7 +265,31 @@ fn test_input_stream_blocked()
             tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
             println!("Delay done");
         }
-        task.await;
+        let _ = task.await;
         println!("Exiting");
     });
 }
+
+#[test]
+fn test_input_stream_header_timeout()
+{
+    let mut rt = Runtime::new().unwrap();
+
+    rt.block_on(async {
+        // One complete record followed by a partial header
+        let (mut client, server) = tokio::net::UnixStream::pair().unwrap();
+        tokio::io::AsyncWriteExt::write_all(
+            &mut client, &[1u8, 5, 0, 1, 0, 0, 0, 0, 1, 5]).await.unwrap();
+        let timeouts = Timeouts{idle: Some(Duration::from_secs(10)),
+                                header: Some(Duration::from_millis(100)),
+                                body: None};
+        let mut framer =
+            RecordInputStream::with_timeouts(Arc::new(Mutex::new(server)),
+                                             timeouts);
+        let first = framer.next().await.unwrap();
+        assert_eq!(first.rec_type, 5);
+        assert!(framer.next().await.is_none());
+        assert!(framer.timed_out());
+        drop(client);
+    });
+}
@@ -6,6 +6,7 @@ use bytes::BytesMut;
 use bytes::BufMut;
 use std::convert::TryFrom;
 use tokio::io::Error;
+use tokio::io::ErrorKind;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -31,14 +32,19 @@ impl<O> RecordOutput<O>
         header.put_u8(rec.version);
         header.put_u8(rec.rec_type);
         header.put_u16(rec.request_id);
-        header.put_u16(u16::try_from(content_len).unwrap());
+        let content_len = match u16::try_from(content_len) {
+            Ok(l) => l,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidInput,
+                                            "Record content too long"))
+        };
+        header.put_u16(content_len);
         header.put_u8(padding_len);
         header.put_u8(0);
 
         let mut output = self.output.lock().await;
-        output.write(&header).await?;
-        output.write(&rec.content_data).await?;
-        output.write(&PADDING[..usize::from(padding_len)]).await?;
+        output.write_all(&header).await?;
+        output.write_all(&rec.content_data).await?;
+        output.write_all(&PADDING[..usize::from(padding_len)]).await?;
 
         Ok(())
     }
@@ -148,7 +148,7 @@ pub enum AppRecord {
 impl AppRecord {
     pub fn encode(self: &AppRecord, request_id: u16) -> Result<Record,Error>
     {
-        let mut rec = Record{request_id: request_id,
+        let mut rec = Record{request_id,
                          version: defs::FCGI_VERSION_1,
                          rec_type: 0,
                          content_data: BytesMut::new()};
@@ -0,0 +1,267 @@
+use std::fmt;
+use std::str::FromStr;
+use std::ops::RangeInclusive;
+use super::error::HelvarError;
+
+/// Valid ranges of the address parts, as checked by the routers
+pub const CLUSTERS: RangeInclusive<u8> = 1..=253;
+pub const ROUTERS: RangeInclusive<u8> = 1..=254;
+pub const SUBNETS: RangeInclusive<u8> = 1..=4;
+pub const DEVICES: RangeInclusive<u8> = 1..=255;
+pub const SUBDEVICES: RangeInclusive<u8> = 1..=16;
+pub const GROUPS: RangeInclusive<u16> = 1..=16383;
+/// Scene blocks and the scenes in each block
+pub const BLOCKS: RangeInclusive<u8> = 1..=8;
+pub const SCENES: RangeInclusive<u8> = 1..=16;
+
+fn parse_part<T>(s: &str, range: &RangeInclusive<T>, err: fn() -> HelvarError)
+                 -> Result<T, HelvarError>
+    where T: FromStr + PartialOrd
+{
+    match T::from_str(s.trim()) {
+        Ok(v) if range.contains(&v) => Ok(v),
+        _ => Err(err())
+    }
+}
+
+fn check<T>(v: T, range: &RangeInclusive<T>, err: fn() -> HelvarError)
+            -> Result<T, HelvarError>
+    where T: PartialOrd
+{
+    if range.contains(&v) {Ok(v)} else {Err(err())}
+}
+
+pub fn parse_cluster(s: &str) -> Result<u8, HelvarError>
+{
+    parse_part(s, &CLUSTERS, || HelvarError::InvalidCluster)
+}
+
+pub fn parse_router(s: &str) -> Result<u8, HelvarError>
+{
+    parse_part(s, &ROUTERS, || HelvarError::InvalidRouter)
+}
+
+pub fn parse_subnet(s: &str) -> Result<u8, HelvarError>
+{
+    parse_part(s, &SUBNETS, || HelvarError::InvalidSubnet)
+}
+
+pub fn parse_device(s: &str) -> Result<u8, HelvarError>
+{
+    parse_part(s, &DEVICES, || HelvarError::InvalidDevice)
+}
+
+pub fn parse_subdevice(s: &str) -> Result<u8, HelvarError>
+{
+    parse_part(s, &SUBDEVICES, || HelvarError::InvalidSubdevice)
+}
+
+pub fn parse_group(s: &str) -> Result<u16, HelvarError>
+{
+    parse_part(s, &GROUPS, || HelvarError::InvalidGroupIndex)
+}
+
+pub fn check_group(group: u16) -> Result<u16, HelvarError>
+{
+    check(group, &GROUPS, || HelvarError::InvalidGroupIndex)
+}
+
+pub fn parse_block(s: &str) -> Result<u8, HelvarError>
+{
+    parse_part(s, &BLOCKS, || HelvarError::InvalidBlock)
+}
+
+pub fn parse_scene(s: &str) -> Result<u8, HelvarError>
+{
+    parse_part(s, &SCENES, || HelvarError::InvalidScene)
+}
+
+pub fn check_block(block: u8) -> Result<u8, HelvarError>
+{
+    check(block, &BLOCKS, || HelvarError::InvalidBlock)
+}
+
+pub fn check_scene(scene: u8) -> Result<u8, HelvarError>
+{
+    check(scene, &SCENES, || HelvarError::InvalidScene)
+}
+
+/// Address of a device, or a subdevice such as one input of a
+/// multisensor, e.g. "1.2.3.4" or "1.2.3.4.1". The cluster and router
+/// are the third and fourth octets of the router's IP address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceAddress
+{
+    pub cluster: u8,
+    pub router: u8,
+    pub subnet: u8,
+    pub device: u8,
+    pub subdevice: Option<u8>
+}
+
+impl DeviceAddress
+{
+    pub fn new(cluster: u8, router: u8, subnet: u8, device: u8)
+               -> Result<DeviceAddress, HelvarError>
+    {
+        Ok(DeviceAddress{
+            cluster: check(cluster, &CLUSTERS, || HelvarError::InvalidCluster)?,
+            router: check(router, &ROUTERS, || HelvarError::InvalidRouter)?,
+            subnet: check(subnet, &SUBNETS, || HelvarError::InvalidSubnet)?,
+            device: check(device, &DEVICES, || HelvarError::InvalidDevice)?,
+            subdevice: None
+        })
+    }
+
+    pub fn with_subdevice(self, subdevice: u8)
+                          -> Result<DeviceAddress, HelvarError>
+    {
+        let subdevice = check(subdevice, &SUBDEVICES,
+                              || HelvarError::InvalidSubdevice)?;
+        Ok(DeviceAddress{subdevice: Some(subdevice), ..self})
+    }
+
+    /// The device a subdevice belongs to
+    pub fn parent(&self) -> DeviceAddress
+    {
+        DeviceAddress{subdevice: None, ..*self}
+    }
+}
+
+impl fmt::Display for DeviceAddress
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}.{}.{}.{}",
+               self.cluster, self.router, self.subnet, self.device)?;
+        if let Some(sub) = self.subdevice {
+            write!(f, ".{}", sub)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DeviceAddress
+{
+    type Err = HelvarError;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let s = s.trim();
+        let s = s.strip_prefix('@').unwrap_or(s);
+        let parts: Vec<&str> = s.split('.').collect();
+        if parts.len() < 4 || parts.len() > 5 {
+            return Err(HelvarError::InvalidDevice);
+        }
+        let address = DeviceAddress{
+            cluster: parse_cluster(parts[0])?,
+            router: parse_router(parts[1])?,
+            subnet: parse_subnet(parts[2])?,
+            device: parse_device(parts[3])?,
+            subdevice: None
+        };
+        match parts.get(4) {
+            Some(sub) => Ok(DeviceAddress{subdevice: Some(parse_subdevice(sub)?),
+                                          ..address}),
+            None => Ok(address)
+        }
+    }
+}
+
+/// Anything a command can be addressed to. Groups are written as in
+/// the protocol, e.g. "G:17".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HelvarAddress
+{
+    Device(DeviceAddress),
+    Group(u16)
+}
+
+impl fmt::Display for HelvarAddress
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            HelvarAddress::Device(a) => a.fmt(f),
+            HelvarAddress::Group(g) => write!(f, "G:{}", g)
+        }
+    }
+}
+
+impl FromStr for HelvarAddress
+{
+    type Err = HelvarError;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let s = s.trim();
+        match s.strip_prefix("G:").or_else(|| s.strip_prefix("g:")) {
+            Some(group) => Ok(HelvarAddress::Group(parse_group(group)?)),
+            None => Ok(HelvarAddress::Device(DeviceAddress::from_str(s)?))
+        }
+    }
+}
+
+impl From<DeviceAddress> for HelvarAddress
+{
+    fn from(address: DeviceAddress) -> HelvarAddress
+    {
+        HelvarAddress::Device(address)
+    }
+}
+
+#[test]
+fn test_address_parse()
+{
+    let a = DeviceAddress::from_str("1.2.3.4").unwrap();
+    assert_eq!(a, DeviceAddress::new(1, 2, 3, 4).unwrap());
+    assert_eq!(a.to_string(), "1.2.3.4");
+    let a = DeviceAddress::from_str("@253.254.4.255.16").unwrap();
+    assert_eq!(a.subdevice, Some(16));
+    assert_eq!(a.to_string(), "253.254.4.255.16");
+    assert_eq!(a.parent().to_string(), "253.254.4.255");
+
+    assert_eq!(HelvarAddress::from_str("G:17").unwrap(),
+               HelvarAddress::Group(17));
+    assert_eq!(HelvarAddress::Group(17).to_string(), "G:17");
+    assert_eq!(HelvarAddress::from_str("1.2.3.4").unwrap(),
+               HelvarAddress::Device(DeviceAddress::new(1, 2, 3, 4).unwrap()));
+}
+
+#[test]
+fn test_address_validation()
+{
+    fn code(s: &str) -> u32
+    {
+        match HelvarAddress::from_str(s) {
+            Err(HelvarError::InvalidCluster) => 2,
+            Err(HelvarError::InvalidRouter) => 3,
+            Err(HelvarError::InvalidSubnet) => 4,
+            Err(HelvarError::InvalidDevice) => 5,
+            Err(HelvarError::InvalidSubdevice) => 6,
+            Err(HelvarError::InvalidGroupIndex) => 1,
+            r => panic!("Unexpected result {:?}", r)
+        }
+    }
+    assert_eq!(code("0.1.1.1"), 2);
+    assert_eq!(code("254.1.1.1"), 2);
+    assert_eq!(code("1.255.1.1"), 3);
+    assert_eq!(code("1.1.5.1"), 4);
+    assert_eq!(code("1.1.1.0"), 5);
+    assert_eq!(code("1.1.1.x"), 5);
+    assert_eq!(code("1.1.1"), 5);
+    assert_eq!(code("1.1.1.1.17"), 6);
+    assert_eq!(code("G:0"), 1);
+    assert_eq!(code("G:16384"), 1);
+    assert!(DeviceAddress::new(1, 1, 1, 1).unwrap()
+            .with_subdevice(0).is_err());
+}
+
+#[test]
+fn test_scene_validation()
+{
+    assert_eq!(parse_block("8").unwrap(), 8);
+    assert_eq!(parse_scene(" 16").unwrap(), 16);
+    assert!(matches!(parse_block("9"), Err(HelvarError::InvalidBlock)));
+    assert!(matches!(check_block(0), Err(HelvarError::InvalidBlock)));
+    assert!(matches!(parse_scene("0"), Err(HelvarError::InvalidScene)));
+    assert!(matches!(check_scene(17), Err(HelvarError::InvalidScene)));
+}
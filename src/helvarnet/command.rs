@@ -0,0 +1,423 @@
+use std::fmt;
+use super::defs as cmd;
+pub use super::address::DeviceAddress;
+
+/// A command parameter. The tag is the letter preceding the value
+/// in the message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Param
+{
+    Group(u16),
+    Block(u8),
+    Scene(u8),
+    /// Fade time in 1/100 s
+    Fade(u32),
+    Level(u8),
+    Proportion(i8),
+    /// Store the scene even if the device is in constant light mode
+    ForceStore(bool),
+    /// Seconds since the Unix epoch
+    Time(i64),
+    /// Seconds of arc, positive east
+    Longitude(i32),
+    /// Seconds of arc, positive north
+    Latitude(i32),
+    /// Seconds from UTC
+    TimeZone(i32),
+    DaylightSaving(bool),
+    /// A cluster, used by CMD_QUERY_ROUTERS
+    Cluster(u8),
+    Device(DeviceAddress)
+}
+
+impl Param
+{
+    pub fn tag(&self) -> &'static str
+    {
+        match self {
+            Param::Group(_) => "G",
+            Param::Block(_) => "B",
+            Param::Scene(_) => "S",
+            Param::Fade(_) => "F",
+            Param::Level(_) => "L",
+            Param::Proportion(_) => "P",
+            Param::ForceStore(_) => "O",
+            Param::Time(_) => "T",
+            Param::Longitude(_) => "E",
+            Param::Latitude(_) => "N",
+            Param::TimeZone(_) => "Z",
+            Param::DaylightSaving(_) => "Y",
+            Param::Cluster(_) | Param::Device(_) => "@"
+        }
+    }
+}
+
+impl fmt::Display for Param
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Param::Cluster(c) => write!(f, "@{}", c),
+            Param::Device(a) => write!(f, "@{}", a),
+            Param::Group(v) => write!(f, "{}:{}", self.tag(), v),
+            Param::Block(v) | Param::Scene(v) | Param::Level(v) =>
+                write!(f, "{}:{}", self.tag(), v),
+            Param::Fade(v) => write!(f, "{}:{}", self.tag(), v),
+            Param::Proportion(v) => write!(f, "{}:{}", self.tag(), v),
+            Param::ForceStore(v) | Param::DaylightSaving(v) =>
+                write!(f, "{}:{}", self.tag(), u8::from(*v)),
+            Param::Time(v) => write!(f, "{}:{}", self.tag(), v),
+            Param::Longitude(v) | Param::Latitude(v) | Param::TimeZone(v) =>
+                write!(f, "{}:{}", self.tag(), v),
+        }
+    }
+}
+
+/// All commands defined in `defs`. Queries return a reply from the
+/// router, the other commands don't.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command
+{
+    RecallSceneGroup{group: u16, block: u8, scene: u8, fade: u32},
+    RecallSceneDevice{address: DeviceAddress, block: u8, scene: u8,
+                      fade: u32},
+    DirectLevelGroup{group: u16, level: u8, fade: u32},
+    DirectLevelDevice{address: DeviceAddress, level: u8, fade: u32},
+    DirectProportionGroup{group: u16, proportion: i8, fade: u32},
+    DirectProportionDevice{address: DeviceAddress, proportion: i8,
+                           fade: u32},
+    ModifyProportionGroup{group: u16, proportion: i8, fade: u32},
+    ModifyProportionDevice{address: DeviceAddress, proportion: i8,
+                           fade: u32},
+    EmergencyFunctionTestGroup{group: u16},
+    EmergencyFunctionTestDevice{address: DeviceAddress},
+    EmergencyDurationTestGroup{group: u16},
+    EmergencyDurationTestDevice{address: DeviceAddress},
+    StopEmergencyTestsGroup{group: u16},
+    StopEmergencyTestsDevice{address: DeviceAddress},
+
+    QueryLampRunningHours{address: DeviceAddress},
+    QueryBallastRunningHours{address: DeviceAddress},
+    QueryMaximumVoltage{address: DeviceAddress},
+    QueryMinimumVoltage{address: DeviceAddress},
+    QueryMaximumTemperature{address: DeviceAddress},
+    QueryMinimumTemperature{address: DeviceAddress},
+
+    QueryClusters,
+    QueryRouters{cluster: u8},
+    /// Last scene recalled in a block
+    QueryLsib{group: u16, block: u8},
+    QueryDeviceType{address: DeviceAddress},
+    QueryDescriptionGroup{group: u16},
+    QueryDescriptionDevice{address: DeviceAddress},
+    QueryDeviceState{address: DeviceAddress},
+    QueryDeviceDisabled{address: DeviceAddress},
+    QueryLampFailure{address: DeviceAddress},
+    QueryDeviceFaulty{address: DeviceAddress},
+    QueryDeviceMissing{address: DeviceAddress},
+    QueryEmergencyBatteryFailure{address: DeviceAddress},
+    QueryMeasurement{address: DeviceAddress},
+    QueryInputs{address: DeviceAddress},
+    QueryLoadLevel{address: DeviceAddress},
+    QueryPowerConsumption{address: DeviceAddress},
+    QueryGroupPowerConsumption{group: u16},
+    QueryEmergencyFunctionTestTime{address: DeviceAddress},
+    QueryEmergencyFunctionTestState{address: DeviceAddress},
+    QueryEmergencyDurationTestTime{address: DeviceAddress},
+    QueryEmergencyDurationTestState{address: DeviceAddress},
+    QueryEmergencyBatteryCharge{address: DeviceAddress},
+    QueryEmergencyBatteryTime{address: DeviceAddress},
+    QueryEmergencyTotalLampTime{address: DeviceAddress},
+    QueryTime,
+    QueryLongitude,
+    QueryLatitude,
+    QueryTimeZone,
+    QueryDaylightSavingTime,
+    QuerySoftwareVersion,
+    QueryHelvarNetVersion,
+
+    StoreSceneGroup{group: u16, block: u8, scene: u8, level: u8,
+                    force: bool},
+    StoreSceneDevice{address: DeviceAddress, block: u8, scene: u8,
+                     level: u8, force: bool},
+    /// Store the current levels as a scene
+    StoreAsSceneGroup{group: u16, block: u8, scene: u8, force: bool},
+    StoreAsSceneDevice{address: DeviceAddress, block: u8, scene: u8,
+                       force: bool},
+    ResetEmergencyTimeGroup{group: u16},
+    ResetEmergencyTimeDevice{address: DeviceAddress},
+    ResetTimeAndLocation,
+    SetTime{time: i64},
+    SetLongitude{longitude: i32},
+    SetLatitude{latitude: i32},
+    SetTimeZone{offset: i32},
+    SetDaylightSavingTime{enabled: bool}
+}
+
+impl Command
+{
+    /// The command number from `defs`
+    pub fn code(&self) -> u32
+    {
+        match self {
+            Command::RecallSceneGroup{..} => cmd::CMD_RECALL_SCENE_GROUP,
+            Command::RecallSceneDevice{..} => cmd::CMD_RECALL_SCENE_DEVICE,
+            Command::DirectLevelGroup{..} => cmd::CMD_DIRECT_LEVEL_GROUP,
+            Command::DirectLevelDevice{..} => cmd::CMD_DIRECT_LEVEL_DEVICE,
+            Command::DirectProportionGroup{..} =>
+                cmd::CMD_DIRECT_PRPORTION_GROUP,
+            Command::DirectProportionDevice{..} =>
+                cmd::CMD_DIRECT_PRPORTION_DEVICE,
+            Command::ModifyProportionGroup{..} =>
+                cmd::CMD_DIRECT_MODIFY_PROPORTION_GROUP,
+            Command::ModifyProportionDevice{..} =>
+                cmd::CMD_DIRECT_MODIFY_PROPORTION_DEVICE,
+            Command::EmergencyFunctionTestGroup{..} =>
+                cmd::CMD_EMERGENCY_FUNCTION_TEST_GROUP,
+            Command::EmergencyFunctionTestDevice{..} =>
+                cmd::CMD_EMERGENCY_FUNCTION_TEST_DEVICE,
+            Command::EmergencyDurationTestGroup{..} =>
+                cmd::CMD_EMERGENCY_DURATION_TEST_GROUP,
+            Command::EmergencyDurationTestDevice{..} =>
+                cmd::CMD_EMERGENCY_DURATION_TEST_DEVICE,
+            Command::StopEmergencyTestsGroup{..} =>
+                cmd::CMD_STOP_EMERGENCY_TESTS_GROUP,
+            Command::StopEmergencyTestsDevice{..} =>
+                cmd::CMD_STOP_EMERGENCY_TESTS_DEVICE,
+            Command::QueryLampRunningHours{..} =>
+                cmd::CMD_QUERY_LAMP_RUNNING_HOURS,
+            Command::QueryBallastRunningHours{..} =>
+                cmd::CMD_QUERY_BALLAST_RUNNING_HOURS,
+            Command::QueryMaximumVoltage{..} => cmd::CMD_QUERY_MAXIMUM_VOLTAGE,
+            Command::QueryMinimumVoltage{..} => cmd::CMD_QUERY_MINIMUM_VOLTAGE,
+            Command::QueryMaximumTemperature{..} =>
+                cmd::CMD_QUERY_MAXIMUM_TEMPERATURE,
+            Command::QueryMinimumTemperature{..} =>
+                cmd::CMD_QUERY_MINIMUM_TEMPERATURE,
+            Command::QueryClusters => cmd::CMD_QUERY_CLUSTERS,
+            Command::QueryRouters{..} => cmd::CMD_QUERY_ROUTERS,
+            Command::QueryLsib{..} => cmd::CMD_QUERY_LSIB,
+            Command::QueryDeviceType{..} => cmd::CMD_QUERY_DEVICE_TYPE,
+            Command::QueryDescriptionGroup{..} =>
+                cmd::CMD_QUERY_DESCRIPTION_GROUP,
+            Command::QueryDescriptionDevice{..} =>
+                cmd::CMD_QUERY_DESCRIPTION_DEVICE,
+            Command::QueryDeviceState{..} => cmd::CMD_QUERY_DEVICE_STATE,
+            Command::QueryDeviceDisabled{..} => cmd::CMD_QUERY_DEVICE_DISABLED,
+            Command::QueryLampFailure{..} => cmd::CMD_QUERY_LAMP_FAILURE,
+            Command::QueryDeviceFaulty{..} => cmd::CMD_QUERY_DEVICE_FAULTY,
+            Command::QueryDeviceMissing{..} => cmd::CMD_QUERY_DEVICE_MISSING,
+            Command::QueryEmergencyBatteryFailure{..} =>
+                cmd::CMD_QUERY_EMERGENCY_BATTERY_FAILURE,
+            Command::QueryMeasurement{..} => cmd::CMD_QUERY_MEASUREMENT,
+            Command::QueryInputs{..} => cmd::CMD_QUERY_INPUTS,
+            Command::QueryLoadLevel{..} => cmd::CMD_QUERY_LOAD_LEVEL,
+            Command::QueryPowerConsumption{..} =>
+                cmd::CMD_QUERY_POWER_CONSUMPTION,
+            Command::QueryGroupPowerConsumption{..} =>
+                cmd::CMD_QUERY_GROUP_POWER_CONSUMPTION,
+            Command::QueryEmergencyFunctionTestTime{..} =>
+                cmd::CMD_QUERY_EMERGENCY_FUNCTION_TEST_TIME,
+            Command::QueryEmergencyFunctionTestState{..} =>
+                cmd::CMD_QUERY_EMERGENCY_FUNCTION_TEST_STATE,
+            Command::QueryEmergencyDurationTestTime{..} =>
+                cmd::CMD_QUERY_EMERGENCY_DURATION_TEST_TIME,
+            Command::QueryEmergencyDurationTestState{..} =>
+                cmd::CMD_QUERY_EMERGENCY_DURATION_TEST_STATE,
+            Command::QueryEmergencyBatteryCharge{..} =>
+                cmd::CMD_QUERY_EMERGENCY_BATTERY_CHARGE,
+            Command::QueryEmergencyBatteryTime{..} =>
+                cmd::CMD_QUERY_EMERGENCY_BATTERY_TIME,
+            Command::QueryEmergencyTotalLampTime{..} =>
+                cmd::CMD_QUERY_EMERGENCY_TOTAL_LAMP_TIME,
+            Command::QueryTime => cmd::CMD_QUERY_TIME,
+            Command::QueryLongitude => cmd::CMD_QUERY_LONGITUDE,
+            Command::QueryLatitude => cmd::CMD_QUERY_LATITUDE,
+            Command::QueryTimeZone => cmd::CMD_QUERY_TIMEZONE,
+            Command::QueryDaylightSavingTime =>
+                cmd::CMD_QUERY_DAYLIGHT_SAVING_TIME,
+            Command::QuerySoftwareVersion => cmd::CMD_QUERY_SOFTWARE_VERSION,
+            Command::QueryHelvarNetVersion => cmd::CMD_QUERY_HELVARNET_VERSION,
+            Command::StoreSceneGroup{..} => cmd::CMD_STORE_SCENE_GROUP,
+            Command::StoreSceneDevice{..} => cmd::CMD_STORE_SCENE_DEVICE,
+            Command::StoreAsSceneGroup{..} => cmd::CMD_STORE_AS_SCENE_GROUP,
+            Command::StoreAsSceneDevice{..} => cmd::CMD_STORE_AS_SCENE_DEVICE,
+            Command::ResetEmergencyTimeGroup{..} =>
+                cmd::CMD_RESET_EMERGENCE_TIME_GROUP,
+            Command::ResetEmergencyTimeDevice{..} =>
+                cmd::CMD_RESET_EMERGENCE_TIME_DEVICE,
+            Command::ResetTimeAndLocation => cmd::CMD_RESET_TIME_AND_LOCATION,
+            Command::SetTime{..} => cmd::CMD_RESET_TIME,
+            Command::SetLongitude{..} => cmd::CMD_SET_LONGITUDE,
+            Command::SetLatitude{..} => cmd::CMD_SET_LATITUDE,
+            Command::SetTimeZone{..} => cmd::CMD_SET_TIMEZONE,
+            Command::SetDaylightSavingTime{..} =>
+                cmd::CMD_SET_DAYLIGHT_SAVING_TIME,
+        }
+    }
+
+    /// True if the router sends a reply to this command
+    pub fn is_query(&self) -> bool
+    {
+        let code = self.code();
+        (cmd::CMD_QUERY_LAMP_RUNNING_HOURS..=cmd::CMD_QUERY_HELVARNET_VERSION)
+            .contains(&code)
+    }
+
+    /// Parameters in the order they are sent. An address always comes last.
+    pub fn params(&self) -> Vec<Param>
+    {
+        match *self {
+            Command::RecallSceneGroup{group, block, scene, fade} =>
+                vec![Param::Group(group), Param::Block(block),
+                     Param::Scene(scene), Param::Fade(fade)],
+            Command::RecallSceneDevice{address, block, scene, fade} =>
+                vec![Param::Block(block), Param::Scene(scene),
+                     Param::Fade(fade), Param::Device(address)],
+            Command::DirectLevelGroup{group, level, fade} =>
+                vec![Param::Group(group), Param::Level(level),
+                     Param::Fade(fade)],
+            Command::DirectLevelDevice{address, level, fade} =>
+                vec![Param::Level(level), Param::Fade(fade),
+                     Param::Device(address)],
+            Command::DirectProportionGroup{group, proportion, fade}
+            | Command::ModifyProportionGroup{group, proportion, fade} =>
+                vec![Param::Group(group), Param::Proportion(proportion),
+                     Param::Fade(fade)],
+            Command::DirectProportionDevice{address, proportion, fade}
+            | Command::ModifyProportionDevice{address, proportion, fade} =>
+                vec![Param::Proportion(proportion), Param::Fade(fade),
+                     Param::Device(address)],
+            Command::EmergencyFunctionTestGroup{group}
+            | Command::EmergencyDurationTestGroup{group}
+            | Command::StopEmergencyTestsGroup{group}
+            | Command::QueryDescriptionGroup{group}
+            | Command::QueryGroupPowerConsumption{group}
+            | Command::ResetEmergencyTimeGroup{group} =>
+                vec![Param::Group(group)],
+            Command::EmergencyFunctionTestDevice{address}
+            | Command::EmergencyDurationTestDevice{address}
+            | Command::StopEmergencyTestsDevice{address}
+            | Command::QueryLampRunningHours{address}
+            | Command::QueryBallastRunningHours{address}
+            | Command::QueryMaximumVoltage{address}
+            | Command::QueryMinimumVoltage{address}
+            | Command::QueryMaximumTemperature{address}
+            | Command::QueryMinimumTemperature{address}
+            | Command::QueryDeviceType{address}
+            | Command::QueryDescriptionDevice{address}
+            | Command::QueryDeviceState{address}
+            | Command::QueryDeviceDisabled{address}
+            | Command::QueryLampFailure{address}
+            | Command::QueryDeviceFaulty{address}
+            | Command::QueryDeviceMissing{address}
+            | Command::QueryEmergencyBatteryFailure{address}
+            | Command::QueryMeasurement{address}
+            | Command::QueryInputs{address}
+            | Command::QueryLoadLevel{address}
+            | Command::QueryPowerConsumption{address}
+            | Command::QueryEmergencyFunctionTestTime{address}
+            | Command::QueryEmergencyFunctionTestState{address}
+            | Command::QueryEmergencyDurationTestTime{address}
+            | Command::QueryEmergencyDurationTestState{address}
+            | Command::QueryEmergencyBatteryCharge{address}
+            | Command::QueryEmergencyBatteryTime{address}
+            | Command::QueryEmergencyTotalLampTime{address}
+            | Command::ResetEmergencyTimeDevice{address} =>
+                vec![Param::Device(address)],
+            Command::QueryRouters{cluster} => vec![Param::Cluster(cluster)],
+            Command::QueryLsib{group, block} =>
+                vec![Param::Group(group), Param::Block(block)],
+            Command::QueryClusters
+            | Command::QueryTime
+            | Command::QueryLongitude
+            | Command::QueryLatitude
+            | Command::QueryTimeZone
+            | Command::QueryDaylightSavingTime
+            | Command::QuerySoftwareVersion
+            | Command::QueryHelvarNetVersion
+            | Command::ResetTimeAndLocation => Vec::new(),
+            Command::StoreSceneGroup{group, block, scene, level, force} =>
+                vec![Param::Group(group), Param::Block(block),
+                     Param::Scene(scene), Param::Level(level),
+                     Param::ForceStore(force)],
+            Command::StoreSceneDevice{address, block, scene, level, force} =>
+                vec![Param::Block(block), Param::Scene(scene),
+                     Param::Level(level), Param::ForceStore(force),
+                     Param::Device(address)],
+            Command::StoreAsSceneGroup{group, block, scene, force} =>
+                vec![Param::Group(group), Param::Block(block),
+                     Param::Scene(scene), Param::ForceStore(force)],
+            Command::StoreAsSceneDevice{address, block, scene, force} =>
+                vec![Param::Block(block), Param::Scene(scene),
+                     Param::ForceStore(force), Param::Device(address)],
+            Command::SetTime{time} => vec![Param::Time(time)],
+            Command::SetLongitude{longitude} =>
+                vec![Param::Longitude(longitude)],
+            Command::SetLatitude{latitude} => vec![Param::Latitude(latitude)],
+            Command::SetTimeZone{offset} => vec![Param::TimeZone(offset)],
+            Command::SetDaylightSavingTime{enabled} =>
+                vec![Param::DaylightSaving(enabled)],
+        }
+    }
+
+    /// Build the message sent to the router, including the terminator.
+    /// Queries are prefixed with '?' and other commands with '>'.
+    pub fn encode(&self, version: u32) -> String
+    {
+        let prefix = if self.is_query() {'?'} else {'>'};
+        let mut msg = format!("{}V:{},C:{}", prefix, version, self.code());
+        for p in self.params() {
+            msg.push(',');
+            msg += &p.to_string();
+        }
+        msg.push('#');
+        msg
+    }
+}
+
+#[cfg(test)]
+const TEST_ADDR: DeviceAddress =
+    DeviceAddress{cluster: 1, router: 2, subnet: 3, device: 4,
+                  subdevice: None};
+
+#[test]
+fn test_command_encode()
+{
+    let c = Command::DirectLevelDevice{address: TEST_ADDR, level: 45,
+                                       fade: 70};
+    assert_eq!(c.encode(3), ">V:3,C:14,L:45,F:70,@1.2.3.4#");
+    let c = Command::QueryDeviceType{address: TEST_ADDR};
+    assert_eq!(c.encode(3), "?V:3,C:104,@1.2.3.4#");
+    let c = Command::RecallSceneGroup{group: 17, block: 2, scene: 5,
+                                      fade: 0};
+    assert_eq!(c.encode(1), ">V:1,C:11,G:17,B:2,S:5,F:0#");
+    let c = Command::StoreSceneDevice{address: TEST_ADDR, block: 1, scene: 3,
+                                      level: 100, force: true};
+    assert_eq!(c.encode(3), ">V:3,C:202,B:1,S:3,L:100,O:1,@1.2.3.4#");
+    let c = Command::DirectProportionGroup{group: 1, proportion: -20,
+                                           fade: 100};
+    assert_eq!(c.encode(3), ">V:3,C:15,G:1,P:-20,F:100#");
+    assert_eq!(Command::QueryRouters{cluster: 5}.encode(3),
+               "?V:3,C:102,@5#");
+    assert_eq!(Command::QueryTime.encode(3), "?V:3,C:185#");
+    assert_eq!(Command::SetTimeZone{offset: 3600}.encode(3),
+               ">V:3,C:244,Z:3600#");
+    assert_eq!(Command::SetLatitude{latitude: 185400}.encode(3),
+               ">V:3,C:243,N:185400#");
+    assert_eq!(Command::SetLongitude{longitude: -3600}.encode(3),
+               ">V:3,C:242,E:-3600#");
+    assert_eq!(Command::SetDaylightSavingTime{enabled: true}.encode(3),
+               ">V:3,C:245,Y:1#");
+}
+
+#[test]
+fn test_command_queries()
+{
+    assert!(Command::QueryClusters.is_query());
+    assert!(Command::QueryLampRunningHours{address: TEST_ADDR}.is_query());
+    assert!(!Command::StopEmergencyTestsGroup{group: 1}.is_query());
+    assert!(!Command::StoreAsSceneGroup{group: 1, block: 1, scene: 1,
+                                        force: false}.is_query());
+    assert!(!Command::ResetTimeAndLocation.is_query());
+}
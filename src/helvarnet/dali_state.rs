@@ -0,0 +1,340 @@
+use std::fmt;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use super::address::{self, DeviceAddress, HelvarAddress};
+use super::emergency::{EmergencyAction, EmergencyState};
+use super::health::DeviceHealth;
+use super::energy::EnergyMeter;
+use super::maintenance::RunningHours;
+
+#[derive(Debug,Clone)]
+pub struct DeviceState {
+    pub address: DeviceAddress,
+    pub device_type: u32,
+    pub intensity: u8,
+    pub description: String,
+    pub health: DeviceHealth,
+    /// Only for emergency luminaires
+    pub emergency: Option<EmergencyState>,
+    /// Only for devices whose power is polled
+    pub power: Option<EnergyMeter>,
+    /// Only for devices whose running hours are polled
+    pub running_hours: Option<RunningHours>,
+    /// Only for sensors
+    pub measurement: Option<Measurement>
+}
+
+/// Latest value read from a sensor
+#[derive(Debug, Clone)]
+pub struct Measurement
+{
+    pub value: f64,
+    pub unit: &'static str,
+    pub updated: SystemTime
+}
+
+impl DeviceState
+{
+    pub fn new(address: DeviceAddress) ->DeviceState
+    {
+        DeviceState{
+            address,
+            device_type: 0,
+            intensity: 0,
+            description: String::new(),
+            health: DeviceHealth::default(),
+            emergency: None,
+            power: None,
+            running_hours: None,
+            measurement: None
+        }
+    }
+}
+
+/// Number of device slots in a subnet, one for every valid device
+/// number
+const SUBNET_DEVICES: usize = *address::DEVICES.end() as usize;
+
+pub struct SubnetState
+{
+    pub index: u8,
+    /// Indexed by device number - 1
+    pub devices: [Option<Box<DeviceState>>; SUBNET_DEVICES]
+}
+
+impl SubnetState {
+    pub fn new(index: u8) -> SubnetState
+    {
+        const NO_DEVICE: Option<Box<DeviceState>> = None;
+        SubnetState{index,
+                    devices: [NO_DEVICE; SUBNET_DEVICES]}
+    }
+}
+impl fmt::Debug for SubnetState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f,"[")?;
+        let mut iter = self.devices.iter().peekable();
+        while let Some(dev) = &mut iter.next()  {
+            (dev as &dyn fmt::Debug).fmt(f)?;
+            if iter.peek().is_some() {
+                write!(f,", ")?;
+            }
+        }
+        write!(f,"]")
+    }
+}
+
+
+/// A scene stored through the API. The routers can't be asked for the
+/// levels of a scene, so only what was stored here is known.
+#[derive(Debug, Clone)]
+pub struct SceneInfo
+{
+    pub scene: u8,
+    /// None if the levels the devices had at the time were stored
+    pub level: Option<u8>,
+    pub stored: SystemTime
+}
+
+/// The scenes of one block of a device or group
+#[derive(Debug, Clone)]
+pub struct SceneBlock
+{
+    pub block: u8,
+    pub scenes: BTreeMap<u8, SceneInfo>,
+    pub last_recalled: Option<u8>,
+    pub recalled: Option<SystemTime>
+}
+
+impl SceneBlock
+{
+    pub fn new(block: u8) -> SceneBlock
+    {
+        SceneBlock{block, scenes: BTreeMap::new(), last_recalled: None,
+                   recalled: None}
+    }
+
+    pub fn store(&mut self, scene: u8, level: Option<u8>)
+    {
+        self.scenes.insert(scene, SceneInfo{scene, level,
+                                            stored: SystemTime::now()});
+    }
+
+    pub fn recall(&mut self, scene: u8)
+    {
+        self.last_recalled = Some(scene);
+        self.recalled = Some(SystemTime::now());
+    }
+}
+
+/// Scene blocks of a device or group, keyed by block number
+pub type SceneBlocks = BTreeMap<u8, SceneBlock>;
+
+/// Last comparison of a router clock with the host clock
+#[derive(Debug, Clone, Copy)]
+pub struct ClockCheck
+{
+    /// Seconds the router was ahead of the host
+    pub drift: i64,
+    pub checked: SystemTime,
+    /// True if the router time was then set from the host clock
+    pub synced: bool
+}
+
+#[derive(Debug)]
+pub struct RouterState {
+    pub subnets: Vec<Option<Box<SubnetState>>>,
+    /// Scene blocks of the devices, keyed by subnet and device
+    pub scenes: BTreeMap<(u8, u8), SceneBlocks>,
+    pub clock: Option<ClockCheck>
+}
+
+impl Default for RouterState
+{
+    fn default() -> RouterState
+    {
+        RouterState::new()
+    }
+}
+
+impl RouterState
+{
+    pub fn new() ->RouterState
+    {
+        RouterState{subnets: Vec::new(), scenes: BTreeMap::new(), clock: None}
+    }
+
+    pub fn get_subnet(&self, subnet: u8) -> Option<&SubnetState>
+    {
+        let subnet = usize::from(subnet);
+        self.subnets.get(subnet.checked_sub(1)?).and_then(|x| x.as_deref())
+    }
+    
+    pub fn get_subnet_mut(&mut self, subnet: u8)
+                              -> Option<&mut SubnetState>
+    {
+        let subnet = usize::from(subnet);
+        self.subnets.get_mut(subnet.checked_sub(1)?).and_then(|x| x.as_deref_mut())
+    }
+
+    pub fn get_device(&self, subnet: u8, addr: u8)
+                          -> Option<&DeviceState>
+    {
+        let sn = self.get_subnet(subnet)?;
+        let addr = usize::from(addr);
+        sn.devices.get(addr.checked_sub(1)?).and_then(|x| x.as_deref())
+    }
+    
+    pub fn get_device_mut(&mut self, subnet: u8, addr: u8)
+                          -> Option<&mut DeviceState>
+    {
+        let sn = self.get_subnet_mut(subnet)?;
+        let addr = usize::from(addr);
+        sn.devices.get_mut(addr.checked_sub(1)?).and_then(|x| x.as_deref_mut())
+    }
+
+    pub fn device_scenes(&self, subnet: u8, addr: u8) -> Option<&SceneBlocks>
+    {
+        self.scenes.get(&(subnet, addr))
+    }
+
+    /// A scene block of a device, created if missing
+    pub fn scene_block_mut(&mut self, subnet: u8, addr: u8, block: u8)
+                           -> &mut SceneBlock
+    {
+        self.scenes.entry((subnet, addr)).or_default()
+            .entry(block).or_insert_with(|| SceneBlock::new(block))
+    }
+
+    /// Add or replace a device, creating its subnet if needed
+    pub fn set_device(&mut self, dev: DeviceState)
+    {
+        let subnet = dev.address.subnet;
+        let index = usize::from(dev.address.device);
+        while self.subnets.len() < usize::from(subnet) {
+            self.subnets.push(None);
+        }
+        let sn = self.subnets[usize::from(subnet) - 1]
+            .get_or_insert_with(|| Box::new(SubnetState::new(subnet)));
+        if let Some(slot) = sn.devices.get_mut(index - 1) {
+            *slot = Some(Box::new(dev));
+        }
+    }
+}
+
+/// A group and the last command sent to it. Groups span the whole
+/// workgroup, so they aren't kept per router.
+#[derive(Debug, Clone)]
+pub struct GroupState
+{
+    pub group: u16,
+    pub description: String,
+    pub level: Option<u8>,
+    pub proportion: Option<i8>,
+    /// Block and scene last recalled
+    pub scene: Option<(u8, u8)>,
+    pub scenes: SceneBlocks,
+    /// Last emergency command sent to the group
+    pub emergency_action: Option<(EmergencyAction, SystemTime)>,
+    pub power: Option<EnergyMeter>
+}
+
+impl GroupState
+{
+    pub fn new(group: u16) -> GroupState
+    {
+        GroupState{group, description: String::new(), level: None,
+                   proportion: None, scene: None, scenes: BTreeMap::new(),
+                   emergency_action: None, power: None}
+    }
+}
+
+/// State of all routers, keyed by cluster and router number, and of
+/// the groups used so far
+#[derive(Debug, Default)]
+pub struct WorkgroupState
+{
+    pub routers: BTreeMap<(u8, u8), RouterState>,
+    pub groups: BTreeMap<u16, GroupState>
+}
+
+impl WorkgroupState
+{
+    pub fn new() -> WorkgroupState
+    {
+        WorkgroupState{routers: BTreeMap::new(), groups: BTreeMap::new()}
+    }
+
+    /// The state of a group, created if missing
+    pub fn group_mut(&mut self, group: u16) -> &mut GroupState
+    {
+        self.groups.entry(group).or_insert_with(|| GroupState::new(group))
+    }
+
+    pub fn get_router(&self, cluster: u8, router: u8) -> Option<&RouterState>
+    {
+        self.routers.get(&(cluster, router))
+    }
+
+    /// The state of a router, created if missing
+    pub fn router_mut(&mut self, cluster: u8, router: u8) -> &mut RouterState
+    {
+        self.routers.entry((cluster, router)).or_default()
+    }
+
+    pub fn get_device(&self, address: &DeviceAddress) -> Option<&DeviceState>
+    {
+        self.get_router(address.cluster, address.router)?
+            .get_device(address.subnet, address.device)
+    }
+
+    pub fn get_device_mut(&mut self, address: &DeviceAddress)
+                          -> Option<&mut DeviceState>
+    {
+        self.routers.get_mut(&(address.cluster, address.router))?
+            .get_device_mut(address.subnet, address.device)
+    }
+
+    pub fn set_device(&mut self, dev: DeviceState)
+    {
+        let address = dev.address;
+        self.router_mut(address.cluster, address.router).set_device(dev);
+    }
+
+    /// Scene blocks of a device or group, if any scene is known
+    pub fn get_scenes(&self, target: &HelvarAddress) -> Option<&SceneBlocks>
+    {
+        match target {
+            HelvarAddress::Device(a) => self.get_router(a.cluster, a.router)?
+                .device_scenes(a.subnet, a.device),
+            HelvarAddress::Group(g) => Some(&self.groups.get(g)?.scenes)
+        }
+    }
+
+    /// A scene block of a device or group, created if missing
+    pub fn scene_block_mut(&mut self, target: &HelvarAddress, block: u8)
+                           -> &mut SceneBlock
+    {
+        match target {
+            HelvarAddress::Device(a) => self.router_mut(a.cluster, a.router)
+                .scene_block_mut(a.subnet, a.device, block),
+            HelvarAddress::Group(g) => self.group_mut(*g).scenes.entry(block)
+                .or_insert_with(|| SceneBlock::new(block))
+        }
+    }
+}
+
+#[test]
+fn test_set_device()
+{
+    let mut state = WorkgroupState::new();
+    for device in &[1, 64, 65, 255] {
+        let address = DeviceAddress::new(1, 2, 3, *device).unwrap();
+        state.set_device(DeviceState::new(address));
+        assert_eq!(state.get_device(&address).unwrap().address, address);
+    }
+    let rs = state.get_router(1, 2).unwrap();
+    assert_eq!(rs.get_subnet(3).unwrap().devices.iter().flatten().count(), 4);
+}
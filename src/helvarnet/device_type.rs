@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::convert::From;
+use std::str::FromStr;
+
+pub struct HelvarDeviceType(u32);
+
+impl From<u32> for HelvarDeviceType
+{
+    fn from(t: u32) -> HelvarDeviceType
+    {
+        HelvarDeviceType(t)
+    }
+}    
+    
+impl HelvarDeviceType
+{
+    pub fn is_load(&self) -> bool
+    {
+        match self.0 & 0xff {
+            0x01 => { // DALI
+                let dali_type = self.0>>8;
+                dali_type <= 8
+            },
+            0x08 => {
+                let dmx_type = self.0>>8;
+                dmx_type == 0x02
+            },
+            _ => false
+        }
+    }
+
+    /// Unit of the value returned by CMD_QUERY_MEASUREMENT, for the
+    /// devices known to measure something. Others can be added with
+    /// `SensorUnits`.
+    pub fn measurement_unit(&self) -> Option<MeasurementUnit>
+    {
+        match self.0 {
+            0x0031_2502 => Some(MeasurementUnit::Lux), // Digidim 312 multisensor
+            _ => None
+        }
+    }
+
+    /// DALI self-contained emergency luminaire (DALI device type 1)
+    pub fn is_emergency(&self) -> bool
+    {
+        self.0 & 0xff == 0x01 && self.0 >> 8 == 1
+    }
+}
+
+/// Parse a device type as returned by CMD_QUERY_DEVICE_TYPE, in hex
+/// like "0x0601" or decimal
+pub fn parse_device_type(s: &str) -> Result<u32, String>
+{
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => u32::from_str(s)
+    }.map_err(|_| format!("Invalid device type \"{}\"", s))
+}
+
+/// What a sensor measures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeasurementUnit
+{
+    Lux,
+    Celsius,
+    /// An input without a known unit, e.g. an analogue input
+    Raw
+}
+
+impl MeasurementUnit
+{
+    /// Empty for a raw value
+    pub fn as_str(&self) -> &'static str
+    {
+        match self {
+            MeasurementUnit::Lux => "lx",
+            MeasurementUnit::Celsius => "°C",
+            MeasurementUnit::Raw => ""
+        }
+    }
+}
+
+impl FromStr for MeasurementUnit
+{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s.trim_start_matches('°').to_ascii_lowercase().as_str() {
+            "lx" | "lux" => Ok(MeasurementUnit::Lux),
+            "c" | "celsius" => Ok(MeasurementUnit::Celsius),
+            "raw" => Ok(MeasurementUnit::Raw),
+            _ => Err(format!("Unknown measurement unit \"{}\", expected \
+                              lx, C or raw", s))
+        }
+    }
+}
+
+/// Measurement units of the sensor types not known by
+/// `HelvarDeviceType::measurement_unit`, e.g. temperature sensors and
+/// input units. Parsed from a list like "0x00312502=lx 0x00123402=C".
+#[derive(Debug, Clone, Default)]
+pub struct SensorUnits
+{
+    pub types: BTreeMap<u32, MeasurementUnit>
+}
+
+impl SensorUnits
+{
+    /// None if the device type doesn't measure anything
+    pub fn get(&self, device_type: u32) -> Option<MeasurementUnit>
+    {
+        self.types.get(&device_type).copied()
+            .or_else(|| HelvarDeviceType::from(device_type).measurement_unit())
+    }
+}
+
+impl FromStr for SensorUnits
+{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let mut units = SensorUnits::default();
+        for entry in s.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|e| !e.is_empty())
+        {
+            let mut parts = entry.splitn(2, '=');
+            let device_type = parse_device_type(parts.next().unwrap_or(""))?;
+            let unit = MeasurementUnit::from_str(parts.next().ok_or_else(
+                || format!("Missing measurement unit in \"{}\"", entry))?)?;
+            units.types.insert(device_type, unit);
+        }
+        Ok(units)
+    }
+}
+
+#[test]
+fn test_device_classes()
+{
+    let sensor = HelvarDeviceType::from(0x0031_2502);
+    assert!(!sensor.is_load());
+    assert_eq!(sensor.measurement_unit(), Some(MeasurementUnit::Lux));
+    let led = HelvarDeviceType::from(0x0601);
+    assert!(led.is_load());
+    assert!(!led.is_emergency());
+    assert!(led.measurement_unit().is_none());
+    assert!(HelvarDeviceType::from(0x0101).is_emergency());
+}
+
+#[test]
+fn test_sensor_units()
+{
+    let units = SensorUnits::from_str("0x00444302=raw, 0x00315602=°C").unwrap();
+    assert_eq!(units.get(0x0044_4302), Some(MeasurementUnit::Raw));
+    assert_eq!(units.get(0x0031_5602).map(|u| u.as_str()), Some("°C"));
+    // Built in
+    assert_eq!(units.get(0x0031_2502), Some(MeasurementUnit::Lux));
+    assert!(units.get(0x0601).is_none());
+    assert_eq!(MeasurementUnit::from_str("Lux"), Ok(MeasurementUnit::Lux));
+    assert!(SensorUnits::from_str("0x0601").is_err());
+    assert!(SensorUnits::from_str("0x0601=volt").is_err());
+    assert!(SensorUnits::from_str("sensor=lx").is_err());
+}
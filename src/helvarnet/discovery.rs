@@ -0,0 +1,66 @@
+use std::net::Ipv4Addr;
+use std::time::SystemTime;
+use super::error::HelvarError;
+use super::router::Router;
+
+/// Routers of one cluster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cluster
+{
+    pub cluster: u8,
+    pub routers: Vec<u8>
+}
+
+/// All routers in a workgroup as seen by one of them
+#[derive(Debug, Clone)]
+pub struct Workgroup
+{
+    /// The router that was asked
+    pub seed: Ipv4Addr,
+    pub clusters: Vec<Cluster>,
+    pub discovered: SystemTime
+}
+
+impl Workgroup
+{
+    /// IP address of a router. The cluster and router numbers are the
+    /// last two octets and the rest is the same for the whole workgroup.
+    pub fn router_address(&self, cluster: u8, router: u8) -> Ipv4Addr
+    {
+        let octets = self.seed.octets();
+        Ipv4Addr::new(octets[0], octets[1], cluster, router)
+    }
+
+    pub fn router_addresses(&self) -> Vec<Ipv4Addr>
+    {
+        self.clusters.iter().flat_map(|c| {
+            c.routers.iter().map(move |&r| self.router_address(c.cluster, r))
+        }).collect()
+    }
+}
+
+/// Find all routers in the workgroup of `seed`
+pub async fn discover(seed: &Router) -> Result<Workgroup,HelvarError>
+{
+    let mut clusters = Vec::new();
+    for cluster in seed.query_clusters().await?.clusters {
+        let routers = seed.query_routers(cluster).await?.routers;
+        clusters.push(Cluster{cluster, routers});
+    }
+    Ok(Workgroup{seed: seed.address(), clusters,
+                 discovered: SystemTime::now()})
+}
+
+#[test]
+fn test_router_addresses()
+{
+    let workgroup = Workgroup{
+        seed: Ipv4Addr::new(10,254,1,1),
+        clusters: vec![Cluster{cluster: 1, routers: vec![1, 2]},
+                       Cluster{cluster: 3, routers: vec![7]}],
+        discovered: SystemTime::now()
+    };
+    assert_eq!(workgroup.router_addresses(),
+               vec![Ipv4Addr::new(10,254,1,1), Ipv4Addr::new(10,254,1,2),
+                    Ipv4Addr::new(10,254,3,7)]);
+}
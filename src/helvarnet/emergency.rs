@@ -0,0 +1,220 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+use super::reply::{DeviceStateFlags, EmergencyTestState};
+
+/// Number of test results kept per device
+pub const MAX_RESULTS: usize = 32;
+
+/// The two emergency tests. A function test briefly switches to the
+/// battery, a duration test runs until the battery is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestKind
+{
+    Function,
+    Duration
+}
+
+impl TestKind
+{
+    pub fn as_str(&self) -> &'static str
+    {
+        match self {
+            TestKind::Function => "function",
+            TestKind::Duration => "duration"
+        }
+    }
+
+    /// Device state flags set while a test is requested, pending or
+    /// in progress
+    pub fn active_flags(&self) -> u32
+    {
+        match self {
+            TestKind::Function => DeviceStateFlags::FUNCTION_TEST_REQUESTED
+                | DeviceStateFlags::FUNCTION_TEST_PENDING
+                | DeviceStateFlags::FUNCTION_TEST_IN_PROGRESS,
+            TestKind::Duration => DeviceStateFlags::DURATION_TEST_REQUESTED
+                | DeviceStateFlags::DURATION_TEST_PENDING
+                | DeviceStateFlags::DURATION_TEST_IN_PROGRESS
+        }
+    }
+}
+
+impl fmt::Display for TestKind
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Emergency commands that can be sent to a device or group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmergencyAction
+{
+    Start(TestKind),
+    /// Stop any test in progress
+    Stop,
+    /// Reset the battery and total lamp time counters
+    Reset
+}
+
+impl EmergencyAction
+{
+    pub fn as_str(&self) -> &'static str
+    {
+        match self {
+            EmergencyAction::Start(TestKind::Function) => "function_test",
+            EmergencyAction::Start(TestKind::Duration) => "duration_test",
+            EmergencyAction::Stop => "stop",
+            EmergencyAction::Reset => "reset"
+        }
+    }
+}
+
+impl FromStr for EmergencyAction
+{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s {
+            "function_test" => Ok(EmergencyAction::Start(TestKind::Function)),
+            "duration_test" => Ok(EmergencyAction::Start(TestKind::Duration)),
+            "stop" => Ok(EmergencyAction::Stop),
+            "reset" => Ok(EmergencyAction::Reset),
+            _ => Err(format!("Unknown emergency action \"{}\"", s))
+        }
+    }
+}
+
+/// Outcome of a completed test
+#[derive(Debug, Clone)]
+pub struct TestResult
+{
+    pub kind: TestKind,
+    pub state: EmergencyTestState,
+    /// When the test was done according to the router
+    pub test_time: Option<String>,
+    /// When the result was seen
+    pub recorded: SystemTime
+}
+
+/// Last known state of one test kind on a device
+#[derive(Debug, Clone, Default)]
+pub struct TestStatus
+{
+    pub state: Option<EmergencyTestState>,
+    pub test_time: Option<String>,
+    pub in_progress: bool,
+    /// When a test was last started through the API
+    pub requested: Option<SystemTime>
+}
+
+/// Emergency test state of a device
+#[derive(Debug, Clone, Default)]
+pub struct EmergencyState
+{
+    pub function: TestStatus,
+    pub duration: TestStatus,
+    /// Percent
+    pub battery_charge: Option<u32>,
+    pub battery_failure: Option<bool>,
+    /// Minutes the battery can power the lamp
+    pub battery_time: Option<u32>,
+    /// Hours the lamp has been powered by the battery
+    pub total_lamp_time: Option<u32>,
+    /// Oldest first
+    pub results: VecDeque<TestResult>,
+    pub updated: Option<SystemTime>
+}
+
+impl EmergencyState
+{
+    pub fn test(&self, kind: TestKind) -> &TestStatus
+    {
+        match kind {
+            TestKind::Function => &self.function,
+            TestKind::Duration => &self.duration
+        }
+    }
+
+    pub fn test_mut(&mut self, kind: TestKind) -> &mut TestStatus
+    {
+        match kind {
+            TestKind::Function => &mut self.function,
+            TestKind::Duration => &mut self.duration
+        }
+    }
+
+    /// True if either test is running
+    pub fn test_in_progress(&self) -> bool
+    {
+        self.function.in_progress || self.duration.in_progress
+    }
+
+    /// The latest result of a test kind
+    pub fn last_result(&self, kind: TestKind) -> Option<&TestResult>
+    {
+        self.results.iter().rev().find(|r| r.kind == kind)
+    }
+
+    /// Update from a poll of the device. A result is recorded when a
+    /// test finishes or the router reports a test time not seen before.
+    pub fn update(&mut self, kind: TestKind, state: EmergencyTestState,
+                  test_time: Option<String>, in_progress: bool)
+    {
+        let last_time = self.last_result(kind).and_then(|r| r.test_time.clone());
+        let status = self.test_mut(kind);
+        let finished = status.in_progress && !in_progress;
+        let new_time = test_time.is_some() && test_time != last_time;
+        status.state = Some(state);
+        status.test_time = test_time.clone();
+        status.in_progress = in_progress;
+        if !in_progress && !state.pending() && (finished || new_time) {
+            if self.results.len() >= MAX_RESULTS {
+                self.results.pop_front();
+            }
+            self.results.push_back(TestResult{kind, state, test_time,
+                                              recorded: SystemTime::now()});
+        }
+        self.updated = Some(SystemTime::now());
+    }
+}
+
+#[test]
+fn test_emergency_results()
+{
+    let mut em = EmergencyState::default();
+    let time = Some("10:00:00 01-Jun-2020".to_string());
+    em.update(TestKind::Function, EmergencyTestState(0), time.clone(), false);
+    assert_eq!(em.results.len(), 1);
+    // Same test seen again
+    em.update(TestKind::Function, EmergencyTestState(0), time.clone(), false);
+    assert_eq!(em.results.len(), 1);
+
+    em.update(TestKind::Function, EmergencyTestState(0x10), time, true);
+    assert!(em.test_in_progress());
+    em.update(TestKind::Function, EmergencyTestState(0x02),
+              Some("10:05:00 01-Jul-2020".to_string()), false);
+    assert_eq!(em.results.len(), 2);
+    let last = em.last_result(TestKind::Function).unwrap();
+    assert!(last.state.failed());
+    assert!(em.last_result(TestKind::Duration).is_none());
+
+    for _ in 0..MAX_RESULTS {
+        em.update(TestKind::Duration, EmergencyTestState(0), None, true);
+        em.update(TestKind::Duration, EmergencyTestState(0), None, false);
+    }
+    assert_eq!(em.results.len(), MAX_RESULTS);
+    assert_eq!(em.results[0].kind, TestKind::Duration);
+}
+
+#[test]
+fn test_emergency_action()
+{
+    assert_eq!(EmergencyAction::from_str("duration_test").unwrap(),
+               EmergencyAction::Start(TestKind::Duration));
+    assert_eq!(EmergencyAction::Stop.as_str(), "stop");
+    assert!(EmergencyAction::from_str("explode").is_err());
+}
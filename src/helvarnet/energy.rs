@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+/// How long power readings are kept
+pub const HISTORY: Duration = Duration::from_secs(24 * 60 * 60);
+/// Readings further apart than this aren't integrated, as nothing is
+/// known about the power in between
+pub const MAX_GAP: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerSample
+{
+    pub time: SystemTime,
+    pub watts: f64
+}
+
+/// Power readings of a device or group and the energy integrated
+/// from them
+#[derive(Debug, Clone, Default)]
+pub struct EnergyMeter
+{
+    /// Oldest first
+    pub history: VecDeque<PowerSample>,
+    /// Watt hours since polling started
+    pub total_wh: f64,
+    /// Watt hours on the local day `day`
+    pub today_wh: f64,
+    /// Local day as days since the Unix epoch
+    pub day: Option<i64>,
+    /// When polling started
+    pub since: Option<SystemTime>
+}
+
+impl EnergyMeter
+{
+    /// Add a reading taken on local day `day`. The energy since the
+    /// previous reading is counted to that day.
+    pub fn record(&mut self, time: SystemTime, watts: f64, day: i64)
+    {
+        let wh = match self.history.back() {
+            Some(last) => match time.duration_since(last.time) {
+                Ok(dt) if dt <= MAX_GAP =>
+                    (last.watts + watts) / 2.0 * dt.as_secs_f64() / 3600.0,
+                _ => 0.0
+            },
+            None => 0.0
+        };
+        if self.day != Some(day) {
+            self.day = Some(day);
+            self.today_wh = 0.0;
+        }
+        self.today_wh += wh;
+        self.total_wh += wh;
+        self.since.get_or_insert(time);
+        self.history.push_back(PowerSample{time, watts});
+        while self.history.front()
+            .is_some_and(|s| s.time + HISTORY < time)
+        {
+            self.history.pop_front();
+        }
+    }
+
+    /// The latest reading
+    pub fn current(&self) -> Option<&PowerSample>
+    {
+        self.history.back()
+    }
+
+    /// Watt hours today. Zero if nothing was read on local day `day`.
+    pub fn today_wh(&self, day: i64) -> f64
+    {
+        if self.day == Some(day) { self.today_wh } else { 0.0 }
+    }
+
+    /// Readings from `from` up to, but not including, `to`
+    pub fn samples(&self, from: Option<SystemTime>, to: Option<SystemTime>)
+                   -> impl Iterator<Item = &PowerSample>
+    {
+        self.history.iter().filter(move |s| {
+            from.is_none_or(|from| s.time >= from)
+                && to.is_none_or(|to| s.time < to)
+        })
+    }
+}
+
+#[test]
+fn test_energy_meter()
+{
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let mut meter = EnergyMeter::default();
+    meter.record(start, 100.0, 1);
+    assert_eq!(meter.total_wh, 0.0);
+    // Half an hour ramping from 100 W to 200 W
+    meter.record(start + Duration::from_secs(1800), 200.0, 1);
+    assert!((meter.today_wh - 75.0).abs() < 1e-9);
+    assert_eq!(meter.current().unwrap().watts, 200.0);
+
+    // New day
+    meter.record(start + Duration::from_secs(3600), 200.0, 2);
+    assert!((meter.today_wh(2) - 100.0).abs() < 1e-9);
+    assert!((meter.total_wh - 175.0).abs() < 1e-9);
+    assert_eq!(meter.today_wh(3), 0.0);
+
+    // Nothing is counted over a gap
+    meter.record(start + Duration::from_secs(3 * 3600), 200.0, 2);
+    assert!((meter.total_wh - 175.0).abs() < 1e-9);
+
+    assert_eq!(meter.samples(Some(start + Duration::from_secs(1800)),
+                             Some(start + Duration::from_secs(3 * 3600)))
+               .count(), 2);
+    meter.record(start + HISTORY + Duration::from_secs(1801), 0.0, 3);
+    assert_eq!(meter.history.len(), 3);
+    assert_eq!(meter.since, Some(start));
+}
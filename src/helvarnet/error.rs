@@ -22,6 +22,8 @@ pub enum HelvarError
     InvalidMessagesType,
     InvalidMessageCommand,
     Timeout,
+    /// A reply that couldn't be decoded
+    InvalidReply(String),
     UnknownErrorCode(u32),
     Other(Box<dyn Error + Send + 'static>)
 }
@@ -110,6 +112,8 @@ impl fmt::Display for HelvarError
     {
         if let HelvarError::UnknownErrorCode(code) = self {
             write!(f,"Unknown error code {}", code)
+        } else if let HelvarError::InvalidReply(reply) = self {
+            write!(f,"Invalid reply: {}", reply)
         } else if let HelvarError::Other(err) = self {
             err.fmt(f)
         } else {
@@ -0,0 +1,211 @@
+use std::str::FromStr;
+
+/// Message prefixes
+const PREFIX_COMMAND: u8 = b'>';
+const PREFIX_INTERNAL: u8 = b'<';
+const PREFIX_REPLY: u8 = b'?';
+const PREFIX_ERROR: u8 = b'!';
+const TERMINATOR: u8 = b'#';
+const ANSWER: char = '=';
+
+/// The part of a message identifying the command, e.g. "V:2,C:104,@1.2.3.4"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header
+{
+    pub version: Option<u32>,
+    pub command: u32,
+    /// Everything after the command number, e.g. "@1.2.3.4" or "G:1,B:2"
+    pub params: String
+}
+
+impl Header
+{
+    pub fn parse(text: &str) -> Option<Header>
+    {
+        let mut rest = text;
+        let mut version = None;
+        if let Some(v) = rest.strip_prefix("V:") {
+            let end = v.find(',').unwrap_or(v.len());
+            version = Some(u32::from_str(&v[..end]).ok()?);
+            rest = v[end..].strip_prefix(',').unwrap_or("");
+        }
+        let c = rest.strip_prefix("C:")?;
+        let end = c.find(',').unwrap_or(c.len());
+        let command = u32::from_str(&c[..end]).ok()?;
+        let params = c[end..].strip_prefix(',').unwrap_or("").to_string();
+        Some(Header{version, command, params})
+    }
+
+    /// Check if this header refers to the same command and parameters
+    /// as `other`. The protocol version isn't compared.
+    pub fn same_command(&self, other: &Header) -> bool
+    {
+        self.command == other.command && self.params == other.params
+    }
+}
+
+/// A complete message received from a router
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message
+{
+    /// Reply to a query
+    Reply{header: Header, value: String},
+    /// Error reply to a query or command
+    Error{header: Header, code: u32},
+    /// A command, normally sent by some other client of the router
+    Command(Header),
+    /// Anything else, e.g. internal messages or other clients' queries
+    Unsolicited(String)
+}
+
+impl Message
+{
+    fn parse(raw: &str) -> Message
+    {
+        let unsolicited = || Message::Unsolicited(raw.to_string());
+        let prefix = raw.as_bytes()[0];
+        let body = &raw[1..raw.len()-1];
+        match prefix {
+            PREFIX_REPLY | PREFIX_ERROR => {
+                let (head, value) = match body.find(ANSWER) {
+                    Some(split) => (&body[..split], &body[split+1..]),
+                    None => return unsolicited()
+                };
+                let header = match Header::parse(head) {
+                    Some(h) => h,
+                    None => return unsolicited()
+                };
+                if prefix == PREFIX_REPLY {
+                    Message::Reply{header, value: value.to_string()}
+                } else {
+                    match u32::from_str(value) {
+                        Ok(code) => Message::Error{header, code},
+                        Err(_) => unsolicited()
+                    }
+                }
+            },
+            PREFIX_COMMAND => match Header::parse(body) {
+                Some(header) => Message::Command(header),
+                None => unsolicited()
+            },
+            _ => unsolicited()
+        }
+    }
+
+    /// Header of replies and errors
+    pub fn reply_header(&self) -> Option<&Header>
+    {
+        match self {
+            Message::Reply{header, ..} | Message::Error{header, ..} =>
+                Some(header),
+            _ => None
+        }
+    }
+}
+
+/// Splits the byte stream from a router into messages
+#[derive(Default)]
+pub struct Framer
+{
+    buffer: Vec<u8>
+}
+
+impl Framer
+{
+    pub fn new() -> Framer
+    {
+        Framer{buffer: Vec::new()}
+    }
+
+    /// Add received bytes
+    pub fn push(&mut self, data: &[u8])
+    {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete message, if any
+    pub fn next_message(&mut self) -> Option<Message>
+    {
+        loop {
+            // Skip anything before the start of a message
+            let start = self.buffer.iter().position(|&b| {
+                b == PREFIX_COMMAND || b == PREFIX_INTERNAL
+                    || b == PREFIX_REPLY || b == PREFIX_ERROR
+            });
+            match start {
+                Some(start) => {
+                    self.buffer.drain(..start);
+                },
+                None => {
+                    self.buffer.clear();
+                    return None;
+                }
+            }
+            let end = self.buffer.iter().position(|&b| b == TERMINATOR)?;
+            let raw: Vec<u8> = self.buffer.drain(..=end).collect();
+            if raw.len() < 2 {
+                continue;
+            }
+            let raw = String::from_utf8_lossy(&raw);
+            return Some(Message::parse(&raw));
+        }
+    }
+}
+
+#[test]
+fn test_framer_split()
+{
+    let mut framer = Framer::new();
+    framer.push(b"\r\n?V:2,C:104,@1.2");
+    assert_eq!(framer.next_message(), None);
+    framer.push(b".3.4=1537#?V:2,C:106,@1.2.3.4=Hall 2#junk");
+    assert_eq!(framer.next_message(),
+               Some(Message::Reply{
+                   header: Header{version: Some(2), command: 104,
+                                  params: "@1.2.3.4".to_string()},
+                   value: "1537".to_string()}));
+    match framer.next_message() {
+        Some(Message::Reply{value, ..}) => assert_eq!(value, "Hall 2"),
+        m => panic!("Unexpected message {:?}", m)
+    }
+    assert_eq!(framer.next_message(), None);
+}
+
+#[test]
+fn test_framer_message_types()
+{
+    let mut framer = Framer::new();
+    framer.push(b"!V:2,C:104,@1.2.3.9=11#>V:2,C:13,G:4,L:50,F:100#\
+                  <V:2,C:12#?V:2,C:152,@1.2.1.1#");
+    match framer.next_message() {
+        Some(Message::Error{header, code}) => {
+            assert_eq!(header.command, 104);
+            assert_eq!(header.params, "@1.2.3.9");
+            assert_eq!(code, 11);
+        },
+        m => panic!("Unexpected message {:?}", m)
+    }
+    match framer.next_message() {
+        Some(Message::Command(header)) => {
+            assert_eq!(header.command, 13);
+            assert_eq!(header.params, "G:4,L:50,F:100");
+        },
+        m => panic!("Unexpected message {:?}", m)
+    }
+    assert_eq!(framer.next_message(),
+               Some(Message::Unsolicited("<V:2,C:12#".to_string())));
+    assert_eq!(framer.next_message(),
+               Some(Message::Unsolicited("?V:2,C:152,@1.2.1.1#".to_string())));
+    assert_eq!(framer.next_message(), None);
+}
+
+#[test]
+fn test_header_match()
+{
+    let query = Header::parse("V:3,C:152,@1.2.1.7").unwrap();
+    let reply = Header::parse("V:2,C:152,@1.2.1.7").unwrap();
+    let other = Header::parse("V:3,C:152,@1.2.1.8").unwrap();
+    assert!(query.same_command(&reply));
+    assert!(!query.same_command(&other));
+    assert!(Header::parse("X:3,C:152").is_none());
+}
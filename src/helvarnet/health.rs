@@ -0,0 +1,144 @@
+use std::time::SystemTime;
+use super::reply::DeviceStateFlags;
+
+/// Faults reported in the device state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault
+{
+    Disabled,
+    LampFailure,
+    Missing,
+    Faulty
+}
+
+impl Fault
+{
+    pub const ALL: [Fault; 4] = [Fault::Disabled, Fault::LampFailure,
+                                 Fault::Missing, Fault::Faulty];
+
+    pub fn as_str(&self) -> &'static str
+    {
+        match self {
+            Fault::Disabled => "disabled",
+            Fault::LampFailure => "lamp_failure",
+            Fault::Missing => "missing",
+            Fault::Faulty => "faulty"
+        }
+    }
+
+    pub fn flag(&self) -> u32
+    {
+        match self {
+            Fault::Disabled => DeviceStateFlags::DISABLED,
+            Fault::LampFailure => DeviceStateFlags::LAMP_FAILURE,
+            Fault::Missing => DeviceStateFlags::MISSING,
+            Fault::Faulty => DeviceStateFlags::FAULTY
+        }
+    }
+
+    /// `state` with the flag of the fault set as given by the query of
+    /// that fault
+    pub fn apply(&self, state: DeviceStateFlags, active: bool)
+                 -> DeviceStateFlags
+    {
+        if active {
+            DeviceStateFlags(state.0 | self.flag())
+        } else {
+            DeviceStateFlags(state.0 & !self.flag())
+        }
+    }
+
+    fn index(&self) -> usize
+    {
+        match self {
+            Fault::Disabled => 0,
+            Fault::LampFailure => 1,
+            Fault::Missing => 2,
+            Fault::Faulty => 3
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FaultState
+{
+    pub active: bool,
+    /// When the fault was last seen to appear or clear. For a fault
+    /// present at the first poll this is the time of that poll.
+    pub changed: Option<SystemTime>
+}
+
+/// Health of a device as polled with CMD_QUERY_DEVICE_STATE
+#[derive(Debug, Clone, Default)]
+pub struct DeviceHealth
+{
+    pub state: Option<DeviceStateFlags>,
+    pub faults: [FaultState; 4],
+    pub checked: Option<SystemTime>
+}
+
+impl DeviceHealth
+{
+    pub fn update(&mut self, state: DeviceStateFlags)
+    {
+        let now = SystemTime::now();
+        let first = self.state.is_none();
+        for fault in Fault::ALL.iter() {
+            let active = state.contains(fault.flag());
+            let fs = &mut self.faults[fault.index()];
+            if fs.active != active || (first && active) {
+                fs.changed = Some(now);
+            }
+            fs.active = active;
+        }
+        self.state = Some(state);
+        self.checked = Some(now);
+    }
+
+    pub fn fault(&self, fault: Fault) -> &FaultState
+    {
+        &self.faults[fault.index()]
+    }
+
+    pub fn active_faults(&self) -> Vec<Fault>
+    {
+        Fault::ALL.iter().copied().filter(|f| self.fault(*f).active).collect()
+    }
+
+    pub fn has_fault(&self) -> bool
+    {
+        self.faults.iter().any(|f| f.active)
+    }
+}
+
+#[test]
+fn test_fault_apply()
+{
+    let state = DeviceStateFlags(DeviceStateFlags::MISSING
+                                 | DeviceStateFlags::IN_EMERGENCY);
+    let state = Fault::Missing.apply(state, false);
+    let state = Fault::Faulty.apply(state, true);
+    assert_eq!(state, DeviceStateFlags(DeviceStateFlags::FAULTY
+                                       | DeviceStateFlags::IN_EMERGENCY));
+}
+
+#[test]
+fn test_device_health()
+{
+    let mut health = DeviceHealth::default();
+    assert!(!health.has_fault());
+    health.update(DeviceStateFlags(DeviceStateFlags::LAMP_FAILURE));
+    assert_eq!(health.active_faults(), vec![Fault::LampFailure]);
+    let appeared = health.fault(Fault::LampFailure).changed.unwrap();
+    assert!(health.fault(Fault::Missing).changed.is_none());
+
+    health.update(DeviceStateFlags(DeviceStateFlags::LAMP_FAILURE
+                                   | DeviceStateFlags::MISSING));
+    assert_eq!(health.fault(Fault::LampFailure).changed, Some(appeared));
+    assert!(health.fault(Fault::Missing).active);
+
+    health.update(DeviceStateFlags(0));
+    assert!(!health.has_fault());
+    assert!(health.fault(Fault::Missing).changed.is_some());
+    assert!(health.fault(Fault::Faulty).changed.is_none());
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::SystemTime;
+use super::device_type::parse_device_type;
+
+/// Running hours as counted by the device
+#[derive(Debug, Clone, Default)]
+pub struct RunningHours
+{
+    pub lamp: Option<u32>,
+    pub ballast: Option<u32>,
+    pub updated: Option<SystemTime>
+}
+
+/// Rated life in hours of the lamp and ballast of a device type, e.g.
+/// "50000/100000". A "-" leaves a part out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RatedLife
+{
+    pub lamp: Option<u32>,
+    pub ballast: Option<u32>
+}
+
+/// Fraction of the rated life used, 1.0 at end of life
+pub fn life_used(hours: Option<u32>, rated: Option<u32>) -> Option<f64>
+{
+    match (hours, rated) {
+        (Some(hours), Some(rated)) if rated > 0 =>
+            Some(f64::from(hours) / f64::from(rated)),
+        _ => None
+    }
+}
+
+impl FromStr for RatedLife
+{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let err = || format!("Invalid rated life \"{}\", expected \
+                              lamp/ballast hours, e.g. \"50000/100000\"", s);
+        let hours = |h: Option<&str>| match h.map(str::trim) {
+            Some("-") => Ok(None),
+            Some(h) => u32::from_str(h).map(Some).map_err(|_| err()),
+            None => Err(err())
+        };
+        let mut parts = s.splitn(2, '/');
+        let lamp = hours(parts.next())?;
+        let ballast = hours(parts.next())?;
+        Ok(RatedLife{lamp, ballast})
+    }
+}
+
+/// Rated life per device type, as returned by CMD_QUERY_DEVICE_TYPE,
+/// with an optional default for other types. Parsed from a list like
+/// "default=50000/100000 0x0601=30000/-".
+#[derive(Debug, Clone, Default)]
+pub struct RatedLifeTable
+{
+    pub types: BTreeMap<u32, RatedLife>,
+    pub default: Option<RatedLife>
+}
+
+impl RatedLifeTable
+{
+    pub fn get(&self, device_type: u32) -> Option<RatedLife>
+    {
+        self.types.get(&device_type).copied().or(self.default)
+    }
+}
+
+impl FromStr for RatedLifeTable
+{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let mut table = RatedLifeTable::default();
+        for entry in s.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|e| !e.is_empty())
+        {
+            let mut parts = entry.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let life = RatedLife::from_str(parts.next().ok_or_else(
+                || format!("Missing rated life in \"{}\"", entry))?)?;
+            if key == "default" {
+                table.default = Some(life);
+                continue;
+            }
+            let device_type = parse_device_type(key)?;
+            table.types.insert(device_type, life);
+        }
+        Ok(table)
+    }
+}
+
+#[test]
+fn test_rated_life_table()
+{
+    let table = RatedLifeTable::from_str(
+        "default=50000/100000, 0x0601=30000/- 257=-/60000").unwrap();
+    assert_eq!(table.get(0x0601), Some(RatedLife{lamp: Some(30000),
+                                                  ballast: None}));
+    assert_eq!(table.get(0x0101).unwrap().ballast, Some(60000));
+    assert_eq!(table.get(0x0001).unwrap().lamp, Some(50000));
+    assert!(RatedLifeTable::from_str("").unwrap().get(1).is_none());
+    assert!(RatedLifeTable::from_str("0x06=30000").is_err());
+    assert!(RatedLifeTable::from_str("lamp=1/2").is_err());
+
+    assert_eq!(life_used(Some(45000), Some(50000)), Some(0.9));
+    assert_eq!(life_used(Some(45000), None), None);
+}
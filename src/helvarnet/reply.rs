@@ -0,0 +1,411 @@
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::error::HelvarError;
+
+/// Decoding of the value part of a query reply, i.e. the text between
+/// '=' and '#'.
+pub trait FromReply: Sized
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>;
+}
+
+fn parse_number<T>(reply: &str) -> Result<T, HelvarError>
+    where T: FromStr
+{
+    reply.trim().parse::<T>()
+        .map_err(|_| HelvarError::InvalidReply(reply.to_string()))
+}
+
+impl FromReply for u32
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        parse_number(reply)
+    }
+}
+
+impl FromReply for i32
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        parse_number(reply)
+    }
+}
+
+/// Power consumption and measurement replies may have decimals
+impl FromReply for f64
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        parse_number(reply)
+    }
+}
+
+/// Replies to the fault queries, 1 or 0
+impl FromReply for bool
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(parse_number::<u32>(reply)? != 0)
+    }
+}
+
+impl FromReply for String
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(reply.to_string())
+    }
+}
+
+/// Reply to CMD_QUERY_DEVICE_STATE
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeviceStateFlags(pub u32);
+
+impl DeviceStateFlags
+{
+    pub const DISABLED: u32 = 0x0000_0001;
+    pub const LAMP_FAILURE: u32 = 0x0000_0002;
+    pub const MISSING: u32 = 0x0000_0004;
+    pub const FAULTY: u32 = 0x0000_0008;
+    pub const REFRESHING: u32 = 0x0000_0010;
+    pub const EMERGENCY_RESTING: u32 = 0x0000_0100;
+    pub const IN_EMERGENCY: u32 = 0x0000_0400;
+    pub const IN_PROLONG: u32 = 0x0000_0800;
+    pub const FUNCTION_TEST_IN_PROGRESS: u32 = 0x0000_1000;
+    pub const DURATION_TEST_IN_PROGRESS: u32 = 0x0000_2000;
+    pub const DURATION_TEST_PENDING: u32 = 0x0001_0000;
+    pub const FUNCTION_TEST_PENDING: u32 = 0x0002_0000;
+    pub const BATTERY_FAILURE: u32 = 0x0004_0000;
+    pub const EMERGENCY_INHIBIT: u32 = 0x0020_0000;
+    pub const FUNCTION_TEST_REQUESTED: u32 = 0x0040_0000;
+    pub const DURATION_TEST_REQUESTED: u32 = 0x0080_0000;
+    pub const UNKNOWN: u32 = 0x0100_0000;
+    pub const OVER_TEMPERATURE: u32 = 0x0200_0000;
+    pub const OVER_CURRENT: u32 = 0x0400_0000;
+    pub const COMMUNICATION_ERROR: u32 = 0x0800_0000;
+    pub const SEVERE_ERROR: u32 = 0x1000_0000;
+    pub const BAD_REPLY: u32 = 0x2000_0000;
+    pub const DEVICE_MISMATCH: u32 = 0x8000_0000;
+
+    pub fn contains(&self, flags: u32) -> bool
+    {
+        self.0 & flags == flags
+    }
+
+    pub fn disabled(&self) -> bool
+    {
+        self.contains(Self::DISABLED)
+    }
+
+    pub fn lamp_failure(&self) -> bool
+    {
+        self.contains(Self::LAMP_FAILURE)
+    }
+
+    pub fn missing(&self) -> bool
+    {
+        self.contains(Self::MISSING)
+    }
+
+    pub fn faulty(&self) -> bool
+    {
+        self.contains(Self::FAULTY)
+    }
+
+    pub fn battery_failure(&self) -> bool
+    {
+        self.contains(Self::BATTERY_FAILURE)
+    }
+
+    /// True if a function or duration test is running
+    pub fn emergency_test_in_progress(&self) -> bool
+    {
+        self.0 & (Self::FUNCTION_TEST_IN_PROGRESS
+                  | Self::DURATION_TEST_IN_PROGRESS) != 0
+    }
+}
+
+impl FromReply for DeviceStateFlags
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(DeviceStateFlags(parse_number(reply)?))
+    }
+}
+
+/// Reply to CMD_QUERY_EMERGENCY_FUNCTION_TEST_STATE or
+/// CMD_QUERY_EMERGENCY_DURATION_TEST_STATE. Zero means the last test
+/// passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EmergencyTestState(pub u32);
+
+impl EmergencyTestState
+{
+    pub const LAMP_FAILURE: u32 = 0x01;
+    pub const BATTERY_FAILURE: u32 = 0x02;
+    pub const FAULTY: u32 = 0x04;
+    pub const FAILURE: u32 = 0x08;
+    pub const TEST_PENDING: u32 = 0x10;
+    pub const UNKNOWN: u32 = 0x20;
+
+    pub fn contains(&self, flags: u32) -> bool
+    {
+        self.0 & flags == flags
+    }
+
+    pub fn passed(&self) -> bool
+    {
+        self.0 == 0
+    }
+
+    pub fn failed(&self) -> bool
+    {
+        self.0 & (Self::LAMP_FAILURE | Self::BATTERY_FAILURE | Self::FAULTY
+                  | Self::FAILURE) != 0
+    }
+
+    pub fn pending(&self) -> bool
+    {
+        self.contains(Self::TEST_PENDING)
+    }
+
+    /// Short description of the most important flag
+    pub fn as_str(&self) -> &'static str
+    {
+        if self.passed() {
+            "pass"
+        } else if self.contains(Self::LAMP_FAILURE) {
+            "lamp_failure"
+        } else if self.contains(Self::BATTERY_FAILURE) {
+            "battery_failure"
+        } else if self.contains(Self::FAULTY) {
+            "faulty"
+        } else if self.contains(Self::FAILURE) {
+            "failure"
+        } else if self.pending() {
+            "pending"
+        } else {
+            "unknown"
+        }
+    }
+}
+
+impl FromReply for EmergencyTestState
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(EmergencyTestState(parse_number(reply)?))
+    }
+}
+
+fn parse_list(reply: &str) -> Result<Vec<u8>, HelvarError>
+{
+    if reply.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    reply.split(',').map(parse_number).collect()
+}
+
+/// Reply to CMD_QUERY_CLUSTERS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterList
+{
+    pub clusters: Vec<u8>
+}
+
+impl FromReply for ClusterList
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(ClusterList{clusters: parse_list(reply)?})
+    }
+}
+
+/// Reply to CMD_QUERY_ROUTERS. The router numbers are the last octet
+/// of the routers' IP addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterList
+{
+    pub routers: Vec<u8>
+}
+
+impl FromReply for RouterList
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(RouterList{routers: parse_list(reply)?})
+    }
+}
+
+/// Reply to CMD_QUERY_TIME
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouterTime
+{
+    /// Seconds since the Unix epoch
+    pub unix_time: i64
+}
+
+impl RouterTime
+{
+    pub fn from_system_time(time: SystemTime) -> RouterTime
+    {
+        let unix_time = match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64)
+        };
+        RouterTime{unix_time}
+    }
+
+    /// Seconds the router is ahead of `time`
+    pub fn drift(&self, time: SystemTime) -> i64
+    {
+        self.unix_time - RouterTime::from_system_time(time).unix_time
+    }
+}
+
+impl FromReply for RouterTime
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(RouterTime{unix_time: parse_number(reply)?})
+    }
+}
+
+/// Reply to CMD_QUERY_LONGITUDE or CMD_QUERY_LATITUDE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coordinate
+{
+    /// Seconds of arc, positive east or north
+    pub seconds: i32
+}
+
+impl Coordinate
+{
+    pub fn from_degrees(degrees: f64) -> Coordinate
+    {
+        Coordinate{seconds: (degrees * 3600.0).round() as i32}
+    }
+
+    pub fn degrees(&self) -> f64
+    {
+        f64::from(self.seconds) / 3600.0
+    }
+}
+
+impl FromReply for Coordinate
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(Coordinate{seconds: parse_number(reply)?})
+    }
+}
+
+/// Reply to CMD_QUERY_TIMEZONE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeZoneOffset
+{
+    /// Seconds from UTC, positive east
+    pub seconds: i32
+}
+
+impl FromReply for TimeZoneOffset
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(TimeZoneOffset{seconds: parse_number(reply)?})
+    }
+}
+
+/// Reply to CMD_QUERY_DAYLIGHT_SAVING_TIME
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DaylightSaving
+{
+    pub enabled: bool
+}
+
+impl FromReply for DaylightSaving
+{
+    fn from_reply(reply: &str) -> Result<Self, HelvarError>
+    {
+        Ok(DaylightSaving{enabled: parse_number::<u32>(reply)? != 0})
+    }
+}
+
+#[test]
+fn test_device_state_reply()
+{
+    // Lamp failure and duration test in progress
+    let state = DeviceStateFlags::from_reply("8194").unwrap();
+    assert!(state.lamp_failure());
+    assert!(state.emergency_test_in_progress());
+    assert!(!state.missing());
+    assert!(!state.faulty());
+
+    let state = DeviceStateFlags::from_reply("2147483648").unwrap();
+    assert!(state.contains(DeviceStateFlags::DEVICE_MISMATCH));
+
+    assert!(DeviceStateFlags::from_reply("bad").is_err());
+}
+
+#[test]
+fn test_emergency_test_state_reply()
+{
+    let state = EmergencyTestState::from_reply("0").unwrap();
+    assert!(state.passed());
+    assert_eq!(state.as_str(), "pass");
+    let state = EmergencyTestState::from_reply("18").unwrap();
+    assert!(state.failed());
+    assert!(state.pending());
+    assert_eq!(state.as_str(), "battery_failure");
+    let state = EmergencyTestState::from_reply("16").unwrap();
+    assert!(!state.failed());
+    assert_eq!(state.as_str(), "pending");
+}
+
+#[test]
+fn test_cluster_and_router_reply()
+{
+    let clusters = ClusterList::from_reply("1,2,253").unwrap();
+    assert_eq!(clusters.clusters, vec![1, 2, 253]);
+    let routers = RouterList::from_reply("1,5,17").unwrap();
+    assert_eq!(routers.routers, vec![1, 5, 17]);
+    let routers = RouterList::from_reply("").unwrap();
+    assert!(routers.routers.is_empty());
+    assert!(RouterList::from_reply("1,,3").is_err());
+    assert!(ClusterList::from_reply("1,256").is_err());
+}
+
+#[test]
+fn test_time_and_location_reply()
+{
+    let time = RouterTime::from_reply("1592394127").unwrap();
+    assert_eq!(time.unix_time, 1592394127);
+    let host = UNIX_EPOCH + std::time::Duration::from_secs(1592394120);
+    assert_eq!(time.drift(host), 7);
+    let long = Coordinate::from_reply("64800").unwrap();
+    assert!((long.degrees() - 18.0).abs() < 1e-9);
+    let lat = Coordinate::from_reply("-122400").unwrap();
+    assert!((lat.degrees() + 34.0).abs() < 1e-9);
+    assert_eq!(Coordinate::from_degrees(59.3293).seconds, 213585);
+    let tz = TimeZoneOffset::from_reply("3600").unwrap();
+    assert_eq!(tz.seconds, 3600);
+    assert!(DaylightSaving::from_reply("1").unwrap().enabled);
+    assert!(!DaylightSaving::from_reply("0").unwrap().enabled);
+}
+
+#[test]
+fn test_fault_reply()
+{
+    assert!(bool::from_reply("1").unwrap());
+    assert!(!bool::from_reply("0").unwrap());
+    assert!(bool::from_reply("yes").is_err());
+}
+
+#[test]
+fn test_power_reply()
+{
+    assert_eq!(f64::from_reply("12.5").unwrap(), 12.5);
+    assert_eq!(f64::from_reply("0").unwrap(), 0.0);
+    assert!(f64::from_reply("W").is_err());
+}
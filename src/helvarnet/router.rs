@@ -0,0 +1,998 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use tokio::sync::{broadcast, oneshot, watch, Mutex, Notify, Semaphore,
+                  SemaphorePermit};
+use super::error::HelvarError;
+use super::address::{self, HelvarAddress};
+use super::emergency::{EmergencyAction, TestKind};
+use super::health::Fault;
+use super::command::{Command, DeviceAddress};
+use super::framer::{Framer, Header, Message};
+use super::reply::{FromReply, DeviceStateFlags, ClusterList, RouterList,
+                   EmergencyTestState, RouterTime, Coordinate, TimeZoneOffset,
+                   DaylightSaving};
+use super::transport::{Transport, TransportKind, TransportReader, TransportWriter};
+use super::transport::MAX_DATAGRAM;
+
+/// Number of unread events kept for each subscriber
+const EVENT_QUEUE_LEN: usize = 64;
+
+/// Maximum number of messages waiting for a reply from the router
+const MAX_IN_FLIGHT: usize = 16;
+
+/// Number of queries in a row without a reply before the connection is
+/// considered dead
+const MAX_TIMEOUTS: usize = 3;
+
+/// Delay before the first reconnection attempt. Doubled for every
+/// failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+type ReplySender = oneshot::Sender<Result<String,HelvarError>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState
+{
+    /// Trying to connect
+    Connecting,
+    Connected,
+    /// Waiting before the next connection attempt
+    Disconnected,
+    /// Closed by the client
+    Closed
+}
+
+impl ConnectionState
+{
+    pub fn as_str(&self) -> &'static str
+    {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Closed => "closed"
+        }
+    }
+}
+
+/// State of the connection to the router
+#[derive(Debug, Clone)]
+pub struct ConnectionStatus
+{
+    pub state: ConnectionState,
+    /// When the connection entered the current state
+    pub since: SystemTime,
+    /// Why the last connection failed or was lost
+    pub last_error: Option<String>
+}
+
+/// A query waiting for its reply
+struct Pending
+{
+    id: u64,
+    header: Header,
+    reply: ReplySender
+}
+
+/// State shared between the client and the connection task
+struct Shared
+{
+    pending: StdMutex<PendingQueries>,
+    // None when not connected
+    output: Mutex<Option<Box<dyn TransportWriter>>>,
+    events: broadcast::Sender<Message>,
+    status: watch::Sender<ConnectionStatus>,
+    status_rx: watch::Receiver<ConnectionStatus>,
+    // Queries in a row that timed out
+    timeouts: AtomicUsize,
+    // Drops the current connection
+    reset: Notify
+}
+
+struct PendingQueries
+{
+    next_id: u64,
+    // In the order the queries were sent
+    queries: Vec<Pending>,
+    // Set when not connected
+    closed: bool
+}
+
+impl Shared
+{
+    fn dispatch(&self, msg: Message)
+    {
+        if let Some(header) = msg.reply_header() {
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(pos) = pending.queries.iter()
+                .position(|p| p.header.same_command(header))
+            {
+                let query = pending.queries.remove(pos);
+                let res = match msg {
+                    Message::Reply{value, ..} => Ok(value),
+                    Message::Error{code, ..} => Err(HelvarError::from_code(code)),
+                    _ => unreachable!()
+                };
+                self.timeouts.store(0, Ordering::Relaxed);
+                // The caller may have timed out
+                let _ = query.reply.send(res);
+                return;
+            }
+        }
+        // No one listening isn't an error
+        let _ = self.events.send(msg);
+    }
+
+    fn set_status(&self, state: ConnectionState, last_error: Option<String>)
+    {
+        let last_error = match last_error {
+            Some(e) => Some(e),
+            None => self.status_rx.borrow().last_error.clone()
+        };
+        let status = ConnectionStatus{state, since: SystemTime::now(),
+                                      last_error};
+        self.status.broadcast(status).unwrap_or(());
+    }
+
+    fn connected(&self)
+    {
+        self.timeouts.store(0, Ordering::Relaxed);
+        self.pending.lock().unwrap().closed = false;
+        self.set_status(ConnectionState::Connected, None);
+    }
+
+    /// Fail all queries in progress
+    fn disconnected(&self, reason: &str)
+    {
+        let mut pending = self.pending.lock().unwrap();
+        pending.closed = true;
+        for query in pending.queries.drain(..) {
+            let _ = query.reply.send(Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                reason.to_string()).into()));
+        }
+    }
+}
+
+/// Validate the block and scene of a scene command
+fn check_scene(block: u8, scene: u8) -> Result<(u8, u8),HelvarError>
+{
+    Ok((address::check_block(block)?, address::check_scene(scene)?))
+}
+
+fn not_connected() -> HelvarError
+{
+    std::io::Error::new(std::io::ErrorKind::NotConnected,
+                        "Not connected to router").into()
+}
+
+/// Wait until the client is closed or dropped
+async fn wait_for_stop(stop: &mut watch::Receiver<bool>)
+{
+    while !*stop.borrow() {
+        if stop.recv().await.is_none() {
+            break;
+        }
+    }
+}
+
+/// Read messages until the connection is lost. Returns the reason.
+async fn read_messages(mut input: Box<dyn TransportReader>, shared: &Shared)
+                       -> String
+{
+    let mut framer = Framer::new();
+    let mut buf = [0u8; MAX_DATAGRAM];
+    loop {
+        let n = tokio::select! {
+            n = input.read(&mut buf) => n,
+            _ = shared.reset.notified() => return "No reply from router".to_string()
+        };
+        match n {
+            Ok(0) => return "Router closed the connection".to_string(),
+            Ok(n) => {
+                framer.push(&buf[..n]);
+                while let Some(msg) = framer.next_message() {
+                    shared.dispatch(msg);
+                }
+            },
+            Err(e) => return e.to_string()
+        }
+    }
+}
+
+/// Keeps a connection to the router, reconnecting with exponential
+/// backoff when it's lost.
+async fn connection_task(addr: Ipv4Addr, transport: Box<dyn Transport>,
+                         shared: Arc<Shared>,
+                         mut stop: watch::Receiver<bool>)
+{
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        shared.set_status(ConnectionState::Connecting, None);
+        let res = tokio::select! {
+            res = transport.open() => res,
+            _ = wait_for_stop(&mut stop) => break
+        };
+        match res {
+            Ok((input, output)) => {
+                backoff = INITIAL_BACKOFF;
+                *shared.output.lock().await = Some(output);
+                shared.connected();
+                let reason = tokio::select! {
+                    reason = read_messages(input, &shared) => reason,
+                    _ = wait_for_stop(&mut stop) => break
+                };
+                shared.disconnected(&reason);
+                shared.output.lock().await.take();
+                eprintln!("Lost connection to router {}: {}",
+                          addr, reason);
+                shared.set_status(ConnectionState::Disconnected, Some(reason));
+            },
+            Err(e) => {
+                shared.set_status(ConnectionState::Disconnected,
+                                  Some(e.to_string()));
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::delay_for(backoff) => {},
+            _ = wait_for_stop(&mut stop) => break
+        }
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+    shared.disconnected("Connection closed");
+    shared.output.lock().await.take();
+    shared.set_status(ConnectionState::Closed, None);
+}
+
+/// Client for the HelvarNet protocol of a single router. The client
+/// can be cloned and used from several tasks at once. Queries are
+/// pipelined over a single connection and the replies are matched to
+/// the queries by command and parameters.
+///
+/// The connection is kept up in the background. While it's down,
+/// commands and queries fail immediately.
+#[derive(Clone)]
+pub struct Router
+{
+    inner: Arc<Inner>
+}
+
+struct Inner
+{
+    addr: Ipv4Addr,
+    helvarnet_version: u32,
+    transport: TransportKind,
+    reply_timeout: Duration,
+    retries: u32,
+    in_flight: Semaphore,
+    /// Set by `close`, after which nothing more is sent
+    closed: AtomicBool,
+    shared: Arc<Shared>,
+    // Stops the connection task when set or dropped
+    stop: watch::Sender<bool>,
+    task: StdMutex<Option<JoinHandle<()>>>
+}
+
+impl Router {
+    /// Start connecting to the router at `addr`. Returns without
+    /// waiting for the connection to be established.
+    pub fn new(addr: &Ipv4Addr, transport: TransportKind) -> Router
+    {
+        Router::with_transport(addr, transport.transport(IpAddr::V4(*addr)))
+    }
+
+    /// Use a transport that isn't necessarily on the default port
+    pub fn with_transport(addr: &Ipv4Addr, transport: Box<dyn Transport>)
+                          -> Router
+    {
+        let (events, _) = broadcast::channel(EVENT_QUEUE_LEN);
+        let (status, status_rx) = watch::channel(ConnectionStatus{
+            state: ConnectionState::Connecting,
+            since: SystemTime::now(),
+            last_error: None
+        });
+        let shared = Arc::new(Shared{
+            pending: StdMutex::new(PendingQueries{next_id: 0,
+                                                  queries: Vec::new(),
+                                                  closed: true}),
+            output: Mutex::new(None),
+            events,
+            status,
+            status_rx,
+            timeouts: AtomicUsize::new(0),
+            reset: Notify::new()
+        });
+        let (stop, stop_rx) = watch::channel(false);
+        let kind = transport.kind();
+        let reply_timeout = transport.reply_timeout();
+        let retries = transport.retries();
+        let task = tokio::spawn(connection_task(*addr, transport,
+                                                shared.clone(), stop_rx));
+        let inner = Inner{addr: *addr, helvarnet_version: 3,
+                          transport: kind, reply_timeout, retries,
+                          in_flight: Semaphore::new(MAX_IN_FLIGHT),
+                          closed: AtomicBool::new(false),
+                          shared, stop,
+                          task: StdMutex::new(Some(task))};
+        Router{inner: Arc::new(inner)}
+    }
+
+    /// Close the connection to the router once the commands in
+    /// progress have completed. Later commands and queries, also from
+    /// clones, fail as not connected. Closing again does nothing.
+    pub async fn close(&self) -> Result<(),HelvarError>
+    {
+        if self.inner.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        // Wait for the commands in progress. The permits are given
+        // back when done, so waiting commands see the client closed.
+        let mut permits = Vec::with_capacity(MAX_IN_FLIGHT);
+        for _ in 0..MAX_IN_FLIGHT {
+            permits.push(self.inner.in_flight.acquire().await);
+        }
+        if let Some(mut output) = self.inner.shared.output.lock().await.take() {
+            output.shutdown().await?;
+        }
+        self.inner.stop.broadcast(true).unwrap_or(());
+        let task = self.inner.task.lock().unwrap().take();
+        if let Some(task) = task {
+            task.await.unwrap_or(());
+        }
+        drop(permits);
+        Ok(())
+    }
+
+    /// Wait for a free slot for a command, unless closed
+    async fn permit(&self) -> Result<SemaphorePermit<'_>,HelvarError>
+    {
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(not_connected());
+        }
+        let permit = self.inner.in_flight.acquire().await;
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(not_connected());
+        }
+        Ok(permit)
+    }
+
+    pub fn address(&self) -> Ipv4Addr
+    {
+        self.inner.addr
+    }
+
+    /// Cluster and router number, the last two octets of the address
+    pub fn cluster_router(&self) -> (u8, u8)
+    {
+        let octets = self.inner.addr.octets();
+        (octets[2], octets[3])
+    }
+
+    pub fn transport(&self) -> TransportKind
+    {
+        self.inner.transport
+    }
+
+    pub fn connection_status(&self) -> ConnectionStatus
+    {
+        self.inner.shared.status_rx.borrow().clone()
+    }
+
+    /// Wait until connected to the router. Returns false if the client
+    /// was closed.
+    pub async fn wait_connected(&self) -> bool
+    {
+        let mut status = self.inner.shared.status_rx.clone();
+        loop {
+            match status.borrow().state {
+                ConnectionState::Connected => return true,
+                ConnectionState::Closed => return false,
+                _ => {}
+            }
+            if status.recv().await.is_none() {
+                return false;
+            }
+        }
+    }
+
+    /// Address of a device connected to this router. The cluster and
+    /// router numbers are the last two octets of the router's IP address.
+    pub fn device_address(&self, subnet: u8, dev: u8)
+                          -> Result<DeviceAddress,HelvarError>
+    {
+        let (cluster, router) = self.cluster_router();
+        DeviceAddress::new(cluster, router, subnet, dev)
+    }
+
+    async fn write(&self, data: &[u8]) -> Result<(),HelvarError>
+    {
+        let mut output = self.inner.shared.output.lock().await;
+        match output.as_mut() {
+            Some(output) => Ok(output.write(data).await?),
+            None => Err(not_connected())
+        }
+    }
+
+    /// Send a raw command string without waiting for a reply
+    pub async fn command(&self, cmd_str: &str) -> Result<(),HelvarError>
+    {
+        let _permit = self.permit().await?;
+        self.write(cmd_str.as_bytes()).await
+    }
+
+    /// Send a raw query string and wait for the matching reply
+    pub async fn query(&self, cmd_str: &str) -> Result<String,HelvarError>
+    {
+        let header = cmd_str.get(1..cmd_str.len().saturating_sub(1))
+            .and_then(Header::parse)
+            .ok_or_else(|| HelvarError::InvalidReply(cmd_str.to_string()))?;
+        let _permit = self.permit().await?;
+        let (reply_tx, mut reply_rx) = oneshot::channel();
+        let id = {
+            let mut pending = self.inner.shared.pending.lock().unwrap();
+            if pending.closed {
+                return Err(not_connected());
+            }
+            let id = pending.next_id;
+            pending.next_id += 1;
+            pending.queries.push(Pending{id, header, reply: reply_tx});
+            id
+        };
+        let mut attempts = 0;
+        let res = loop {
+            if let Err(e) = self.write(cmd_str.as_bytes()).await {
+                break Err(e);
+            }
+            match tokio::time::timeout(self.inner.reply_timeout,
+                                       &mut reply_rx).await {
+                Ok(Ok(reply)) => return reply,
+                Ok(Err(_)) => break Err(HelvarError::Timeout),
+                // Either the query or the reply was lost, send it again
+                Err(_) if attempts < self.inner.retries => attempts += 1,
+                Err(_) => {
+                    let shared = &self.inner.shared;
+                    if shared.timeouts.fetch_add(1, Ordering::Relaxed) + 1
+                        >= MAX_TIMEOUTS
+                    {
+                        shared.reset.notify();
+                    }
+                    break Err(HelvarError::Timeout);
+                }
+            }
+        };
+        // Forget the query so a late reply isn't taken for a new one
+        let mut pending = self.inner.shared.pending.lock().unwrap();
+        pending.queries.retain(|p| p.id != id);
+        res
+    }
+
+    /// Receive messages from the router that aren't replies to our own
+    /// queries
+    pub fn subscribe(&self) -> broadcast::Receiver<Message>
+    {
+        self.inner.shared.events.subscribe()
+    }
+
+    /// Send a command that has no reply. Commands aren't retried, over
+    /// UDP a lost command goes unnoticed.
+    pub async fn send(&self, command: &Command) -> Result<(),HelvarError>
+    {
+        self.command(&command.encode(self.inner.helvarnet_version)).await
+    }
+
+    /// Send a query and return the reply
+    pub async fn send_query(&self, command: &Command)
+                            -> Result<String,HelvarError>
+    {
+        self.query(&command.encode(self.inner.helvarnet_version)).await
+    }
+
+    /// Send a query and decode the reply
+    pub async fn query_as<T>(&self, command: &Command)
+                             -> Result<T,HelvarError>
+        where T: FromReply
+    {
+        let reply = self.send_query(command).await?;
+        T::from_reply(&reply)
+    }
+
+    pub async fn query_device_type(&self, address: &DeviceAddress)
+                                   -> Result<u32,HelvarError>
+    {
+        self.query_as(&Command::QueryDeviceType{address: *address}).await
+    }
+
+    pub async fn query_device_description(&self, address: &DeviceAddress)
+                                          -> Result<String,HelvarError>
+    {
+        self.send_query(&Command::QueryDescriptionDevice{address: *address}).await
+    }
+
+    pub async fn query_load_level(&self, address: &DeviceAddress)
+                                  -> Result<u32,HelvarError>
+    {
+        self.query_as(&Command::QueryLoadLevel{address: *address}).await
+    }
+
+    pub async fn query_device_state(&self, address: &DeviceAddress)
+                                    -> Result<DeviceStateFlags,HelvarError>
+    {
+        self.query_as(&Command::QueryDeviceState{address: *address}).await
+    }
+
+    /// Whether the device has `fault`, with the query made for that
+    /// fault rather than CMD_QUERY_DEVICE_STATE
+    pub async fn query_fault(&self, address: &DeviceAddress, fault: Fault)
+                             -> Result<bool,HelvarError>
+    {
+        let address = *address;
+        self.query_as(&match fault {
+            Fault::Disabled => Command::QueryDeviceDisabled{address},
+            Fault::LampFailure => Command::QueryLampFailure{address},
+            Fault::Missing => Command::QueryDeviceMissing{address},
+            Fault::Faulty => Command::QueryDeviceFaulty{address}
+        }).await
+    }
+
+    pub async fn query_lamp_running_hours(&self, address: &DeviceAddress)
+                                          -> Result<u32,HelvarError>
+    {
+        self.query_as(&Command::QueryLampRunningHours{address: *address}).await
+    }
+
+    pub async fn query_ballast_running_hours(&self, address: &DeviceAddress)
+                                             -> Result<u32,HelvarError>
+    {
+        self.query_as(&Command::QueryBallastRunningHours{
+            address: *address}).await
+    }
+
+    /// Value measured by a sensor, in the unit given by
+    /// `HelvarDeviceType::measurement_unit`
+    pub async fn query_measurement(&self, address: &DeviceAddress)
+                                   -> Result<f64,HelvarError>
+    {
+        self.query_as(&Command::QueryMeasurement{address: *address}).await
+    }
+
+    /// Power consumption of a device in watts
+    pub async fn query_power_consumption(&self, address: &DeviceAddress)
+                                         -> Result<f64,HelvarError>
+    {
+        self.query_as(&Command::QueryPowerConsumption{address: *address}).await
+    }
+
+    /// Power consumption of the devices in a group in watts
+    pub async fn query_group_power_consumption(&self, group: u16)
+                                               -> Result<f64,HelvarError>
+    {
+        let group = address::check_group(group)?;
+        self.query_as(&Command::QueryGroupPowerConsumption{group}).await
+    }
+
+    pub async fn query_clusters(&self) -> Result<ClusterList,HelvarError>
+    {
+        self.query_as(&Command::QueryClusters).await
+    }
+
+    pub async fn query_routers(&self, cluster: u8)
+                               -> Result<RouterList,HelvarError>
+    {
+        self.query_as(&Command::QueryRouters{cluster}).await
+    }
+
+    pub async fn set_direct_level_device(&self, address: &DeviceAddress,
+                                         level: u8, fade: u32)
+                                         -> Result<(),HelvarError>
+    {
+        self.send(&Command::DirectLevelDevice{address: *address,
+                                              level, fade}).await
+    }
+
+    /// Description of a group. Groups belong to the workgroup, so any
+    /// router can be asked.
+    pub async fn query_group_description(&self, group: u16)
+                                         -> Result<String,HelvarError>
+    {
+        let group = address::check_group(group)?;
+        self.send_query(&Command::QueryDescriptionGroup{group}).await
+    }
+
+    pub async fn set_direct_level_group(&self, group: u16, level: u8,
+                                        fade: u32) -> Result<(),HelvarError>
+    {
+        let group = address::check_group(group)?;
+        self.send(&Command::DirectLevelGroup{group, level, fade}).await
+    }
+
+    /// Set the devices of a group to a proportion, -100 to 100, of their
+    /// range between the current level and the minimum or maximum level
+    pub async fn set_direct_proportion_group(&self, group: u16,
+                                             proportion: i8, fade: u32)
+                                             -> Result<(),HelvarError>
+    {
+        let group = address::check_group(group)?;
+        self.send(&Command::DirectProportionGroup{group, proportion,
+                                                  fade}).await
+    }
+
+    pub async fn recall_scene_group(&self, group: u16, block: u8, scene: u8,
+                                    fade: u32) -> Result<(),HelvarError>
+    {
+        let group = address::check_group(group)?;
+        let (block, scene) = check_scene(block, scene)?;
+        self.send(&Command::RecallSceneGroup{group, block, scene,
+                                             fade}).await
+    }
+
+    pub async fn recall_scene_device(&self, address: &DeviceAddress,
+                                     block: u8, scene: u8, fade: u32)
+                                     -> Result<(),HelvarError>
+    {
+        let (block, scene) = check_scene(block, scene)?;
+        self.send(&Command::RecallSceneDevice{address: *address, block, scene,
+                                              fade}).await
+    }
+
+    /// Store `level` as a scene of every device in a group. Devices in
+    /// constant light mode are only changed if `force` is set.
+    pub async fn store_scene_group(&self, group: u16, block: u8, scene: u8,
+                                   level: u8, force: bool)
+                                   -> Result<(),HelvarError>
+    {
+        let group = address::check_group(group)?;
+        let (block, scene) = check_scene(block, scene)?;
+        self.send(&Command::StoreSceneGroup{group, block, scene, level,
+                                            force}).await
+    }
+
+    pub async fn store_scene_device(&self, address: &DeviceAddress,
+                                    block: u8, scene: u8, level: u8,
+                                    force: bool) -> Result<(),HelvarError>
+    {
+        let (block, scene) = check_scene(block, scene)?;
+        self.send(&Command::StoreSceneDevice{address: *address, block, scene,
+                                             level, force}).await
+    }
+
+    /// Store the current levels of the devices in a group as a scene
+    pub async fn store_as_scene_group(&self, group: u16, block: u8,
+                                      scene: u8, force: bool)
+                                      -> Result<(),HelvarError>
+    {
+        let group = address::check_group(group)?;
+        let (block, scene) = check_scene(block, scene)?;
+        self.send(&Command::StoreAsSceneGroup{group, block, scene,
+                                              force}).await
+    }
+
+    pub async fn store_as_scene_device(&self, address: &DeviceAddress,
+                                       block: u8, scene: u8, force: bool)
+                                       -> Result<(),HelvarError>
+    {
+        let (block, scene) = check_scene(block, scene)?;
+        self.send(&Command::StoreAsSceneDevice{address: *address, block,
+                                               scene, force}).await
+    }
+
+    /// Start or stop emergency tests, or reset the emergency counters,
+    /// of a device or group
+    pub async fn emergency_action(&self, target: &HelvarAddress,
+                                  action: EmergencyAction)
+                                  -> Result<(),HelvarError>
+    {
+        let command = match *target {
+            HelvarAddress::Group(group) => {
+                let group = address::check_group(group)?;
+                match action {
+                    EmergencyAction::Start(TestKind::Function) =>
+                        Command::EmergencyFunctionTestGroup{group},
+                    EmergencyAction::Start(TestKind::Duration) =>
+                        Command::EmergencyDurationTestGroup{group},
+                    EmergencyAction::Stop =>
+                        Command::StopEmergencyTestsGroup{group},
+                    EmergencyAction::Reset =>
+                        Command::ResetEmergencyTimeGroup{group}
+                }
+            },
+            HelvarAddress::Device(address) => match action {
+                EmergencyAction::Start(TestKind::Function) =>
+                    Command::EmergencyFunctionTestDevice{address},
+                EmergencyAction::Start(TestKind::Duration) =>
+                    Command::EmergencyDurationTestDevice{address},
+                EmergencyAction::Stop =>
+                    Command::StopEmergencyTestsDevice{address},
+                EmergencyAction::Reset =>
+                    Command::ResetEmergencyTimeDevice{address}
+            }
+        };
+        self.send(&command).await
+    }
+
+    /// Result of the last test of a kind
+    pub async fn query_emergency_test_state(&self, address: &DeviceAddress,
+                                            kind: TestKind)
+                                            -> Result<EmergencyTestState,
+                                                      HelvarError>
+    {
+        let address = *address;
+        self.query_as(&match kind {
+            TestKind::Function =>
+                Command::QueryEmergencyFunctionTestState{address},
+            TestKind::Duration =>
+                Command::QueryEmergencyDurationTestState{address}
+        }).await
+    }
+
+    /// Time of the last test of a kind, as formatted by the router
+    pub async fn query_emergency_test_time(&self, address: &DeviceAddress,
+                                           kind: TestKind)
+                                           -> Result<String,HelvarError>
+    {
+        let address = *address;
+        self.send_query(&match kind {
+            TestKind::Function =>
+                Command::QueryEmergencyFunctionTestTime{address},
+            TestKind::Duration =>
+                Command::QueryEmergencyDurationTestTime{address}
+        }).await
+    }
+
+    /// Battery charge in percent
+    pub async fn query_emergency_battery_charge(&self, address: &DeviceAddress)
+                                                -> Result<u32,HelvarError>
+    {
+        self.query_as(&Command::QueryEmergencyBatteryCharge{
+            address: *address}).await
+    }
+
+    pub async fn query_emergency_battery_failure(&self,
+                                                 address: &DeviceAddress)
+                                                 -> Result<bool,HelvarError>
+    {
+        let failure: u32 = self.query_as(
+            &Command::QueryEmergencyBatteryFailure{address: *address}).await?;
+        Ok(failure != 0)
+    }
+
+    /// Minutes the battery can power the lamp
+    pub async fn query_emergency_battery_time(&self, address: &DeviceAddress)
+                                              -> Result<u32,HelvarError>
+    {
+        self.query_as(&Command::QueryEmergencyBatteryTime{
+            address: *address}).await
+    }
+
+    /// Hours the lamp has been powered by the battery
+    pub async fn query_emergency_total_lamp_time(&self,
+                                                 address: &DeviceAddress)
+                                                 -> Result<u32,HelvarError>
+    {
+        self.query_as(&Command::QueryEmergencyTotalLampTime{
+            address: *address}).await
+    }
+
+    pub async fn query_time(&self) -> Result<RouterTime,HelvarError>
+    {
+        self.query_as(&Command::QueryTime).await
+    }
+
+    pub async fn query_longitude(&self) -> Result<Coordinate,HelvarError>
+    {
+        self.query_as(&Command::QueryLongitude).await
+    }
+
+    pub async fn query_latitude(&self) -> Result<Coordinate,HelvarError>
+    {
+        self.query_as(&Command::QueryLatitude).await
+    }
+
+    pub async fn query_time_zone(&self) -> Result<TimeZoneOffset,HelvarError>
+    {
+        self.query_as(&Command::QueryTimeZone).await
+    }
+
+    pub async fn query_daylight_saving(&self)
+                                       -> Result<DaylightSaving,HelvarError>
+    {
+        self.query_as(&Command::QueryDaylightSavingTime).await
+    }
+
+    pub async fn set_time(&self, time: RouterTime) -> Result<(),HelvarError>
+    {
+        self.send(&Command::SetTime{time: time.unix_time}).await
+    }
+
+    pub async fn set_longitude(&self, longitude: Coordinate)
+                               -> Result<(),HelvarError>
+    {
+        self.send(&Command::SetLongitude{longitude: longitude.seconds}).await
+    }
+
+    pub async fn set_latitude(&self, latitude: Coordinate)
+                              -> Result<(),HelvarError>
+    {
+        self.send(&Command::SetLatitude{latitude: latitude.seconds}).await
+    }
+
+    pub async fn set_time_zone(&self, offset: TimeZoneOffset)
+                               -> Result<(),HelvarError>
+    {
+        self.send(&Command::SetTimeZone{offset: offset.seconds}).await
+    }
+
+    pub async fn set_daylight_saving(&self, dst: DaylightSaving)
+                                     -> Result<(),HelvarError>
+    {
+        self.send(&Command::SetDaylightSavingTime{enabled: dst.enabled}).await
+    }
+
+    /// Reset the time and location of the router to the factory
+    /// defaults
+    pub async fn reset_time_and_location(&self) -> Result<(),HelvarError>
+    {
+        self.send(&Command::ResetTimeAndLocation).await
+    }
+
+    /// The scene last recalled in a block of a group
+    pub async fn query_last_scene_in_block(&self, group: u16, block: u8)
+                                           -> Result<u32,HelvarError>
+    {
+        let group = address::check_group(group)?;
+        let block = address::check_block(block)?;
+        self.query_as(&Command::QueryLsib{group, block}).await
+    }
+}
+
+#[cfg(test)]
+use tokio::runtime::Runtime;
+#[cfg(test)]
+use tokio::net::{TcpListener, UdpSocket};
+#[cfg(test)]
+use tokio::prelude::*;
+#[cfg(test)]
+use super::transport::{TcpTransport, UdpTransport};
+
+#[cfg(test)]
+fn addr(subnet: u8, device: u8) -> DeviceAddress
+{
+    DeviceAddress::new(1, 2, subnet, device).unwrap()
+}
+
+#[test]
+fn test_router_pipelined_queries()
+{
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let transport = TcpTransport::new(listener.local_addr().unwrap());
+        let router = Router::with_transport(&Ipv4Addr::new(10,0,1,2),
+                                            Box::new(transport));
+        let (mut server, _) = listener.accept().await.unwrap();
+        assert!(router.wait_connected().await);
+        let mut events = router.subscribe();
+
+        let r = router.clone();
+        let first = tokio::spawn(async move {
+            r.query_load_level(&addr(1, 3)).await
+        });
+        let r = router.clone();
+        let second = tokio::spawn(async move {
+            r.query_device_type(&addr(1, 4)).await
+        });
+
+        // Wait for both queries before replying in the opposite order
+        let mut received = Vec::new();
+        let mut buf = [0u8; 256];
+        while received.iter().filter(|&&b| b == b'#').count() < 2 {
+            let n = server.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+        server.write_all(b">V:2,C:13,G:4,L:50,F:100#\
+                           ?V:2,C:104,@1.2.1.4=1537#\
+                           !V:2,C:152,@1.2.1.3=11#").await.unwrap();
+        match first.await.unwrap() {
+            Err(HelvarError::NoSuchDevice) => {},
+            r => panic!("Unexpected result {:?}", r)
+        }
+        assert_eq!(second.await.unwrap().unwrap(), 1537);
+        match events.recv().await.unwrap() {
+            Message::Command(header) => assert_eq!(header.command, 13),
+            m => panic!("Unexpected event {:?}", m)
+        }
+
+        // Pending queries fail when the connection is lost
+        let r = router.clone();
+        let third = tokio::spawn(async move {
+            r.query_load_level(&addr(1, 5)).await
+        });
+        let n = server.read(&mut buf).await.unwrap();
+        assert!(n > 0);
+        drop(server);
+        assert!(third.await.unwrap().is_err());
+        assert!(router.query_load_level(&addr(1, 5)).await.is_err());
+        assert_ne!(router.connection_status().state, ConnectionState::Connected);
+    });
+}
+
+#[test]
+fn test_router_reconnect()
+{
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let transport = TcpTransport::new(listener.local_addr().unwrap());
+        let router = Router::with_transport(&Ipv4Addr::new(10,0,1,2),
+                                            Box::new(transport));
+        let (server, _) = listener.accept().await.unwrap();
+        assert!(router.wait_connected().await);
+        drop(server);
+
+        // The client connects again after a delay
+        let (mut server, _) = listener.accept().await.unwrap();
+        assert!(router.wait_connected().await);
+        let status = router.connection_status();
+        assert_eq!(status.last_error.as_deref(),
+                   Some("Router closed the connection"));
+        let r = router.clone();
+        let query = tokio::spawn(async move {
+            r.query_load_level(&addr(1, 3)).await
+        });
+        let mut buf = [0u8; 256];
+        let n = server.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"?V:3,C:152,@1.2.1.3#");
+        server.write_all(b"?V:2,C:152,@1.2.1.3=42#").await.unwrap();
+        assert_eq!(query.await.unwrap().unwrap(), 42);
+
+        router.close().await.unwrap();
+        assert!(!router.wait_connected().await);
+
+        // Closed for the clones too, and closing again doesn't hang
+        let r = router.clone();
+        let address = addr(1, 3);
+        let query = tokio::time::timeout(Duration::from_secs(5),
+                                         r.query_load_level(&address));
+        match query.await.expect("Query on a closed router hung") {
+            Err(HelvarError::Other(e)) => assert_eq!(
+                e.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+                Some(std::io::ErrorKind::NotConnected)),
+            r => panic!("Unexpected result {:?}", r)
+        }
+        let command = r.command(">V:3,C:14,@1.2.1.3#");
+        assert!(tokio::time::timeout(Duration::from_secs(5), command).await
+                .expect("Command on a closed router hung").is_err());
+        tokio::time::timeout(Duration::from_secs(5), router.close()).await
+            .expect("Second close hung").unwrap();
+    });
+}
+
+#[test]
+fn test_router_udp_retry()
+{
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let transport = UdpTransport::new(server.local_addr().unwrap());
+        let router = Router::with_transport(&Ipv4Addr::new(10,0,1,2),
+                                            Box::new(transport));
+        assert!(router.wait_connected().await);
+        let r = router.clone();
+        let query = tokio::spawn(async move {
+            r.query_load_level(&addr(1, 3)).await
+        });
+        let mut buf = [0u8; 256];
+        // Ignore the first attempt and answer the retry
+        let (n, _) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"?V:3,C:152,@1.2.1.3#");
+        let (n, client) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"?V:3,C:152,@1.2.1.3#");
+        server.send_to(b"?V:2,C:152,@1.2.1.3=17#", &client).await.unwrap();
+        assert_eq!(query.await.unwrap().unwrap(), 17);
+        router.close().await.unwrap();
+    });
+}
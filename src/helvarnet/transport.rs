@@ -0,0 +1,298 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Port for HelvarNet over TCP
+pub const TCP_PORT: u16 = 50000;
+/// Port for HelvarNet over UDP
+pub const UDP_PORT: u16 = 50001;
+
+/// Idle time before TCP keepalive probes are sent
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Largest datagram expected from a router. Readers should be given a
+/// buffer at least this large.
+pub const MAX_DATAGRAM: usize = 2048;
+
+/// How messages are carried to and from a router
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind
+{
+    Tcp,
+    Udp
+}
+
+impl TransportKind
+{
+    pub fn port(&self) -> u16
+    {
+        match self {
+            TransportKind::Tcp => TCP_PORT,
+            TransportKind::Udp => UDP_PORT
+        }
+    }
+
+    /// Create a transport to the router at `addr` using the default port
+    pub fn transport(&self, addr: IpAddr) -> Box<dyn Transport>
+    {
+        let socket = SocketAddr::new(addr, self.port());
+        match self {
+            TransportKind::Tcp => Box::new(TcpTransport::new(socket)),
+            TransportKind::Udp => Box::new(UdpTransport::new(socket))
+        }
+    }
+}
+
+impl FromStr for TransportKind
+{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(TransportKind::Tcp),
+            "udp" => Ok(TransportKind::Udp),
+            _ => Err(format!("Unknown transport \"{}\"", s))
+        }
+    }
+}
+
+impl fmt::Display for TransportKind
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(match self {
+            TransportKind::Tcp => "tcp",
+            TransportKind::Udp => "udp"
+        })
+    }
+}
+
+/// Receiving side of an open transport
+#[async_trait]
+pub trait TransportReader: Send
+{
+    /// Returns 0 when the other end has closed the connection
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Sending side of an open transport
+#[async_trait]
+pub trait TransportWriter: Send
+{
+    /// Send one or more complete messages
+    async fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    async fn shutdown(&mut self) -> io::Result<()>;
+}
+
+/// A way of reaching a router
+#[async_trait]
+pub trait Transport: Send + Sync
+{
+    async fn open(&self) -> io::Result<(Box<dyn TransportReader>,
+                                        Box<dyn TransportWriter>)>;
+
+    /// Time to wait for a reply before the query is sent again or fails
+    fn reply_timeout(&self) -> Duration;
+
+    /// Number of times a query is sent again when no reply is received
+    fn retries(&self) -> u32;
+
+    fn kind(&self) -> TransportKind;
+}
+
+/// HelvarNet over a TCP connection
+pub struct TcpTransport
+{
+    socket: SocketAddr
+}
+
+impl TcpTransport
+{
+    pub fn new(socket: SocketAddr) -> TcpTransport
+    {
+        TcpTransport{socket}
+    }
+}
+
+#[async_trait]
+impl TransportReader for OwnedReadHalf
+{
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        AsyncReadExt::read(self, buf).await
+    }
+}
+
+#[async_trait]
+impl TransportWriter for OwnedWriteHalf
+{
+    async fn write(&mut self, data: &[u8]) -> io::Result<()>
+    {
+        self.write_all(data).await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()>
+    {
+        AsyncWriteExt::shutdown(self).await
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport
+{
+    async fn open(&self) -> io::Result<(Box<dyn TransportReader>,
+                                        Box<dyn TransportWriter>)>
+    {
+        let stream = TcpStream::connect(self.socket).await?;
+        stream.set_keepalive(Some(KEEPALIVE)).unwrap_or(());
+        let (input, output) = stream.into_split();
+        Ok((Box::new(input), Box::new(output)))
+    }
+
+    fn reply_timeout(&self) -> Duration
+    {
+        Duration::from_secs(5)
+    }
+
+    fn retries(&self) -> u32
+    {
+        // TCP takes care of lost packets
+        0
+    }
+
+    fn kind(&self) -> TransportKind
+    {
+        TransportKind::Tcp
+    }
+}
+
+/// HelvarNet over UDP. There's no connection to lose, but any datagram
+/// may be, so queries are retried. Commands without a reply are sent
+/// once: a lost command can't be noticed, and sending it again could
+/// repeat it, e.g. step a level twice.
+pub struct UdpTransport
+{
+    socket: SocketAddr
+}
+
+impl UdpTransport
+{
+    pub fn new(socket: SocketAddr) -> UdpTransport
+    {
+        UdpTransport{socket}
+    }
+}
+
+struct UdpReader(RecvHalf);
+
+#[async_trait]
+impl TransportReader for UdpReader
+{
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        // One byte extra to tell a datagram that was cut short
+        let mut datagram = [0u8; MAX_DATAGRAM + 1];
+        loop {
+            let n = self.0.recv(&mut datagram).await?;
+            if n > MAX_DATAGRAM.min(buf.len()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "Datagram from router too large"));
+            }
+            // An empty datagram would look like a closed connection
+            if n > 0 {
+                buf[..n].copy_from_slice(&datagram[..n]);
+                return Ok(n);
+            }
+        }
+    }
+}
+
+struct UdpWriter(SendHalf);
+
+#[async_trait]
+impl TransportWriter for UdpWriter
+{
+    async fn write(&mut self, data: &[u8]) -> io::Result<()>
+    {
+        self.0.send(data).await?;
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transport for UdpTransport
+{
+    async fn open(&self) -> io::Result<(Box<dyn TransportReader>,
+                                        Box<dyn TransportWriter>)>
+    {
+        let local: SocketAddr = if self.socket.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        }.parse().unwrap();
+        let socket = UdpSocket::bind(local).await?;
+        // Only accept datagrams from the router
+        socket.connect(self.socket).await?;
+        let (input, output) = socket.split();
+        Ok((Box::new(UdpReader(input)), Box::new(UdpWriter(output))))
+    }
+
+    fn reply_timeout(&self) -> Duration
+    {
+        Duration::from_millis(1500)
+    }
+
+    fn retries(&self) -> u32
+    {
+        3
+    }
+
+    fn kind(&self) -> TransportKind
+    {
+        TransportKind::Udp
+    }
+}
+
+#[test]
+fn test_transport_kind()
+{
+    assert_eq!(TransportKind::from_str("UDP").unwrap(), TransportKind::Udp);
+    assert_eq!(TransportKind::from_str("tcp").unwrap(), TransportKind::Tcp);
+    assert!(TransportKind::from_str("serial").is_err());
+    assert_eq!(TransportKind::Udp.port(), 50001);
+    assert_eq!(TransportKind::Tcp.to_string(), "tcp");
+}
+
+#[cfg(test)]
+use tokio::runtime::Runtime;
+
+#[test]
+fn test_udp_datagram_size()
+{
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let transport = UdpTransport::new(server.local_addr().unwrap());
+        let (mut input, mut output) = transport.open().await.unwrap();
+        output.write(b"?V:3,C:101#").await.unwrap();
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let (_, client) = server.recv_from(&mut buf).await.unwrap();
+        let long = vec![b'x'; 1500];
+        server.send_to(&long, &client).await.unwrap();
+        assert_eq!(input.read(&mut buf).await.unwrap(), 1500);
+        let too_long = vec![b'x'; MAX_DATAGRAM + 1];
+        server.send_to(&too_long, &client).await.unwrap();
+        assert!(input.read(&mut buf).await.is_err());
+    });
+}
@@ -12,3 +12,21 @@ pub mod fast_cgi {
     pub mod decoder;
     pub mod defs;
 }
+
+pub mod helvarnet {
+    pub mod error;
+    pub mod defs;
+    pub mod address;
+    pub mod command;
+    pub mod reply;
+    pub mod framer;
+    pub mod transport;
+    pub mod device_type;
+    pub mod dali_state;
+    pub mod router;
+    pub mod discovery;
+    pub mod emergency;
+    pub mod health;
+    pub mod energy;
+    pub mod maintenance;
+}
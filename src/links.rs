@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use helvar_cgi::helvarnet::address::{DeviceAddress, HelvarAddress};
+
+/// Builds URLs for the resources served by the API. The URLs are
+/// based on where the web server mounted the application so they stay
+/// valid behind any reverse proxy prefix.
+pub struct Links
+{
+    base: String
+}
+
+impl Links
+{
+    /// Create from the CGI parameters of a request
+    pub fn from_params(params: &BTreeMap<String,String>) -> Links
+    {
+        let script = params.get("SCRIPT_NAME").map_or("", |s| s.as_str());
+        let script = script.trim_end_matches('/');
+        let base = match params.get("HTTP_HOST") {
+            Some(host) if !host.is_empty() => {
+                let scheme = match params.get("REQUEST_SCHEME") {
+                    Some(scheme) => scheme.as_str(),
+                    None => match params.get("HTTPS") {
+                        Some(https) if https == "on" || https == "1" => "https",
+                        _ => "http"
+                    }
+                };
+                format!("{}://{}{}", scheme, host, script)
+            },
+            // Without a host name the best we can do is a path
+            _ => script.to_string()
+        };
+        Links{base}
+    }
+
+    pub fn root(&self) -> String
+    {
+        format!("{}/", self.base)
+    }
+
+    pub fn workgroup(&self) -> String
+    {
+        format!("{}/workgroup", self.base)
+    }
+
+    pub fn router(&self, cluster: u8, router: u8) -> String
+    {
+        format!("{}/{}/{}", self.base, cluster, router)
+    }
+
+    pub fn subnet(&self, cluster: u8, router: u8, subnet: u8) -> String
+    {
+        format!("{}/{}", self.router(cluster, router), subnet)
+    }
+
+    pub fn device(&self, address: &DeviceAddress) -> String
+    {
+        format!("{}/{}", self.subnet(address.cluster, address.router,
+                                     address.subnet), address.device)
+    }
+
+    pub fn groups(&self) -> String
+    {
+        format!("{}/groups", self.base)
+    }
+
+    pub fn group(&self, group: u16) -> String
+    {
+        format!("{}/{}", self.groups(), group)
+    }
+
+    /// URL template for setting the level of a group
+    pub fn group_level(&self, group: u16) -> String
+    {
+        format!("{}?level={{level}}", self.group(group))
+    }
+
+    /// URL template for setting the proportion of a group
+    pub fn group_proportion(&self, group: u16) -> String
+    {
+        format!("{}?proportion={{proportion}}", self.group(group))
+    }
+
+    /// URL template for recalling a scene of a group
+    pub fn group_scene(&self, group: u16) -> String
+    {
+        format!("{}?scene={{block}}.{{scene}}", self.group(group))
+    }
+
+    /// URL of a device or group
+    pub fn target(&self, target: &HelvarAddress) -> String
+    {
+        match target {
+            HelvarAddress::Device(address) => self.device(address),
+            HelvarAddress::Group(group) => self.group(*group)
+        }
+    }
+
+    pub fn scenes(&self, target: &HelvarAddress) -> String
+    {
+        format!("{}/scenes", self.target(target))
+    }
+
+    pub fn scene_block(&self, target: &HelvarAddress, block: u8) -> String
+    {
+        format!("{}/{}", self.scenes(target), block)
+    }
+
+    pub fn scene(&self, target: &HelvarAddress, block: u8, scene: u8) -> String
+    {
+        format!("{}/{}", self.scene_block(target, block), scene)
+    }
+
+    /// Devices that have a fault
+    pub fn faults(&self) -> String
+    {
+        format!("{}/faults", self.base)
+    }
+
+    /// Emergency luminaires of all routers
+    pub fn emergency_devices(&self) -> String
+    {
+        format!("{}/emergency", self.base)
+    }
+
+    /// Emergency test compliance report, "csv" or "html"
+    pub fn emergency_report(&self, format: &str) -> String
+    {
+        format!("{}/report?format={}", self.emergency_devices(), format)
+    }
+
+    /// Emergency tests of a device or group
+    pub fn emergency(&self, target: &HelvarAddress) -> String
+    {
+        format!("{}/emergency", self.target(target))
+    }
+
+    /// Power and energy of all polled devices and groups
+    pub fn energy_overview(&self) -> String
+    {
+        format!("{}/energy", self.base)
+    }
+
+    /// Power readings of a device or group
+    pub fn energy(&self, target: &HelvarAddress) -> String
+    {
+        format!("{}/energy", self.target(target))
+    }
+
+    /// Time and location of a router
+    pub fn clock(&self, cluster: u8, router: u8) -> String
+    {
+        format!("{}/clock", self.router(cluster, router))
+    }
+
+    /// Devices near the end of their rated life
+    pub fn maintenance(&self) -> String
+    {
+        format!("{}/maintenance", self.base)
+    }
+
+    pub fn maintenance_csv(&self) -> String
+    {
+        format!("{}?format=csv", self.maintenance())
+    }
+
+    /// URL template for setting the level of a device
+    pub fn device_level(&self, address: &DeviceAddress) -> String
+    {
+        format!("{}?level={{level}}", self.device(address))
+    }
+}
+
+#[test]
+fn test_links()
+{
+    let mut params = BTreeMap::new();
+    params.insert("SCRIPT_NAME".to_string(), "/lights/api".to_string());
+    let links = Links::from_params(&params);
+    assert_eq!(links.root(), "/lights/api/");
+    let address = DeviceAddress::new(1, 3, 2, 17).unwrap();
+    assert_eq!(links.device(&address), "/lights/api/1/3/2/17");
+
+    params.insert("HTTP_HOST".to_string(), "example.com:8080".to_string());
+    params.insert("HTTPS".to_string(), "on".to_string());
+    let links = Links::from_params(&params);
+    assert_eq!(links.subnet(1, 3, 1), "https://example.com:8080/lights/api/1/3/1");
+    let address = DeviceAddress::new(1, 3, 1, 3).unwrap();
+    assert_eq!(links.device_level(&address),
+               "https://example.com:8080/lights/api/1/3/1/3?level={level}");
+    assert_eq!(links.group_scene(17),
+               "https://example.com:8080/lights/api/groups/17?scene={block}.{scene}");
+    assert_eq!(links.scene(&address.into(), 2, 5),
+               "https://example.com:8080/lights/api/1/3/1/3/scenes/2/5");
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::{FromRawFd, RawFd};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// A listening socket inherited from the parent process
+pub enum Listener
+{
+    Unix(UnixListener),
+    Tcp(TcpListener)
+}
+
+pub enum Connection
+{
+    Unix(UnixStream),
+    Tcp(TcpStream)
+}
+
+fn socket_domain(fd: RawFd) -> Result<libc::c_int, io::Error>
+{
+    let mut domain: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_DOMAIN,
+                         &mut domain as *mut libc::c_int as *mut libc::c_void,
+                         &mut len)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(domain)
+}
+
+impl Listener
+{
+    /// Take ownership of a listening socket. The socket type is
+    /// detected from the file descriptor.
+    pub fn from_raw_fd(fd: RawFd) -> Result<Listener, io::Error>
+    {
+        match socket_domain(fd)? {
+            libc::AF_UNIX => {
+                let std_listener = unsafe {
+                    std::os::unix::net::UnixListener::from_raw_fd(fd)
+                };
+                Ok(Listener::Unix(UnixListener::from_std(std_listener)?))
+            },
+            libc::AF_INET | libc::AF_INET6 => {
+                let std_listener = unsafe {
+                    std::net::TcpListener::from_raw_fd(fd)
+                };
+                Ok(Listener::Tcp(TcpListener::from_std(std_listener)?))
+            },
+            d => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    format!("Unsupported socket domain {} \
+                                             for file descriptor {}", d, fd)))
+        }
+    }
+
+    pub async fn accept(&mut self) -> Result<Connection, io::Error>
+    {
+        match self {
+            Listener::Unix(l) => {
+                let (stream, _) = l.accept().await?;
+                Ok(Connection::Unix(stream))
+            },
+            Listener::Tcp(l) => {
+                let (stream, _) = l.accept().await?;
+                Ok(Connection::Tcp(stream))
+            }
+        }
+    }
+}
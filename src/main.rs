@@ -1,22 +1,21 @@
-use std::net::{IpAddr, SocketAddr};
-use tokio::net::TcpStream;
-use tokio;
 use tokio::prelude::*;
-use tokio::stream::StreamExt;
 use tokio::sync::Mutex;
-use std::time::Duration;
-use std::os::unix::io::FromRawFd;
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::signal::unix::{signal, SignalKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
 use std::sync::Mutex as StdMutex;
 use std::fmt;
 use std::convert::TryFrom;
-use std::convert::TryInto;
 use std::str::FromStr;
-use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
 use std::env;
+use std::future::Future;
 
 extern crate helvar_cgi;
-use helvar_cgi::fast_cgi::decoder::Decoder;
+use helvar_cgi::fast_cgi::decoder::{Decoder, DecoderStats};
 
 #[macro_use]
 extern crate async_trait;
@@ -25,165 +24,69 @@ extern crate async_trait;
 extern crate serde_json;
 use serde_json as json;
 
-pub mod helvar_error;
-use helvar_error::HelvarError;
-pub mod helvar_defs;
-use helvar_defs as cmd;
-pub mod dali_state;
-use dali_state::RouterState;
-use dali_state::SubnetState;
-use dali_state::DeviceState;
-
-pub mod helvar_device_type;
-use helvar_device_type::HelvarDeviceType;
+use helvar_cgi::helvarnet::error::HelvarError;
+use helvar_cgi::helvarnet::address::{self, DeviceAddress, HelvarAddress};
+use helvar_cgi::helvarnet::router::{Router, ConnectionState, ConnectionStatus};
+use helvar_cgi::helvarnet::transport::TransportKind;
+use helvar_cgi::helvarnet::discovery::{self, Workgroup};
+use helvar_cgi::helvarnet::dali_state::{RouterState, WorkgroupState};
+use helvar_cgi::helvarnet::dali_state::{ClockCheck, Measurement};
+use helvar_cgi::helvarnet::dali_state::SubnetState;
+use helvar_cgi::helvarnet::dali_state::DeviceState;
+use helvar_cgi::helvarnet::dali_state::GroupState;
+use helvar_cgi::helvarnet::dali_state::{SceneBlock, SceneBlocks, SceneInfo};
+use helvar_cgi::helvarnet::device_type::{HelvarDeviceType, SensorUnits};
+use helvar_cgi::helvarnet::emergency::{EmergencyAction, EmergencyState};
+use helvar_cgi::helvarnet::emergency::{TestKind, TestStatus};
+use helvar_cgi::helvarnet::reply::DeviceStateFlags;
+use helvar_cgi::helvarnet::reply::{Coordinate, DaylightSaving, RouterTime};
+use helvar_cgi::helvarnet::reply::TimeZoneOffset;
+use helvar_cgi::helvarnet::health::{DeviceHealth, Fault};
+use helvar_cgi::helvarnet::energy::{self, EnergyMeter};
+use helvar_cgi::helvarnet::maintenance::{RatedLifeTable, RunningHours};
 
 pub mod wrapper_error;
 use wrapper_error::WrapperError;
 
+pub mod systemd;
+pub mod links;
+use links::Links;
+pub mod config;
+use config::RouterConfig;
+pub mod listener;
+use listener::{Listener, Connection};
+pub mod query_string;
+pub mod dates;
+pub mod report;
+use report::Report;
+pub mod schedule;
+use schedule::Schedule;
+pub mod replacement;
+use replacement::ReplacementList;
+pub mod scheduler;
+use scheduler::{ScheduledTest, SchedulerArc, TestScheduler};
+use scheduler::emergency_test_task;
+
 use helvar_cgi::fast_cgi as fcgi;
-use fcgi::input_stream::RecordInputStream;
+use fcgi::input_stream::{RecordInputStream, Timeouts};
 use fcgi::record_output::RecordOutput;
+use fcgi::defs::FCGI_LISTENSOCK_FILENO;
 
 use fcgi::request::{Request,RequestHandler};
     
-struct Router {
-    addr: Ipv4Addr,
-    stream: TcpStream,
-    helvarnet_version: u32
-}
-
-impl Router {
-    async fn connect(addr: &Ipv4Addr) -> Result<Router,HelvarError>
-    {
-        let socket = SocketAddr::new(IpAddr::V4(addr.clone()),50000);
-        let stream = TcpStream::connect(socket).await?;
-
-        let router = Router{addr: addr.clone(), stream, helvarnet_version: 3};
-        Ok(router)
-    }
-
-    fn device_arg(&self, subnet: u8, dev: u8) -> String
-    {
-        format!("@{}.{}.{}", self.addr, subnet,dev)
-    }
-    
-    async fn command(&mut self, cmd_str: &str) -> Result<(),HelvarError>
-    {
-        let cmd_bytes = cmd_str.as_bytes();
-        match self.stream.write(cmd_bytes).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into())
-        }
-    }
-    
-    async fn query(&mut self, cmd_str: &str) -> Result<String,HelvarError>
-    {
-        let cmd_bytes = cmd_str.as_bytes();
-        self.stream.write(cmd_bytes).await?;
-
-        let mut buf = [0u8; 256];
-        let mut line = Vec::<u8>::new();
-        loop {
-            let read =  self.stream.read(&mut buf);
-            let timeout = tokio::time::timeout(Duration::from_secs(5),
-                                               read);
-            let n = match timeout.await {
-                Err(_) => return Err(HelvarError::Timeout),
-                Ok(res) => {
-                    match res {
-                        Err(e) => 
-                            return Err(HelvarError::from_error(Box::new(e))),
-                        Ok(n) => n
-                    }
-                }
-
-            };
-            let mut recv:&[u8] = &buf[0..n];
-            while !recv.is_empty() {
-                if line.len() == 0 {
-                    let start = recv.iter()
-                        .position(|&b| b == b'?' || b == b'!')
-                        .unwrap_or(recv.len());
-                    recv = &recv[start..];
-                }
-                let end_found = recv.iter().position(|&b| b == b'#');
-                let end = end_found.map_or(recv.len(), |x| x+1);
-                line.extend_from_slice(&recv[..end]);
-                recv = &recv[end..];
-                if end_found.is_some() {
-                    let cmd_end = cmd_bytes.len() - 1; 
-                    if cmd_end < line.len() 
-                        && line[1..cmd_end] == cmd_bytes[1..cmd_end]
-                        && line[cmd_end] == b'='
-                    {
-                        let reply =
-                            std::str::from_utf8(&line[cmd_end+1..line.len()-1])?;
-                        if line[0] == b'?' {
-                            // println!("{} {}",n, std::str::from_utf8(&line).unwrap());
-                            return Ok(reply.to_string());
-                        } else if line[0] == b'!' {
-                            let err_code = reply.parse::<u32>()?;
-                            return Err(HelvarError::from_code(err_code));
-                        }
-                    }
-                    line.clear();
-                }
-//                println!("recv: {:?}", recv);
-            }
-        }
-        
-    }
-    
-    async fn query_device_type(&mut self, subnet: u8, dev: u8) -> Result<u32,HelvarError>
-    {
-        let cmd_str = format!("?V:{},C:{},{}#", self.helvarnet_version, 
-                              cmd::CMD_QUERY_DEVICE_TYPE,
-                              self.device_arg(subnet, dev));
-        let reply = self.query(&cmd_str).await?;
-        let t = match reply.parse::<u32>() {
-            Ok(v) => v,
-            Err(e) => return Err(HelvarError::Other(Box::new(e)))
-        };
-        Ok(t)
-    }
-
-      async fn query_device_description(&mut self, subnet: u8, dev: u8) -> Result<String,HelvarError>
-    {
-        let cmd_str = format!("?V:{},C:{},{}#", self.helvarnet_version, 
-                              cmd::CMD_QUERY_DESCRIPTION_DEVICE,
-                              self.device_arg(subnet, dev));
-        self.query(&cmd_str).await
-    }
-      async fn query_load_level(&mut self, subnet: u8, dev: u8) -> Result<u32,HelvarError>
-    {
-        let cmd_str = format!("?V:{},C:{},{}#", self.helvarnet_version, 
-                              cmd::CMD_QUERY_LOAD_LEVEL,
-                              self.device_arg(subnet, dev));
-        let reply = self.query(&cmd_str).await?;
-        let t = match reply.parse::<u32>() {
-            Ok(v) => v,
-            Err(e) => return Err(HelvarError::Other(Box::new(e)))
-        };
-        Ok(t)
-    }
-
-    async fn set_direct_level_device(&mut self, subnet: u8, dev: u8,
-                                     level: u32, fade: u32) 
-                                     -> Result<(),HelvarError>
-    {
-        let cmd_str = format!("?V:{},C:{},L:{},F:{},{}#",
-                              self.helvarnet_version, 
-                              cmd::CMD_DIRECT_LEVEL_DEVICE,
-                              level, fade,
-                              self.device_arg(subnet, dev));
-        self.command(&cmd_str).await
-    }
-}
-
+/// Serves the API. Cloned for every FastCGI connection.
+#[derive(Clone)]
 struct Handler
 {
-    router_state: RouterStateArc,
-    router_control: RouterArc
+    state: StateArc,
+    routers: RoutersArc,
+    workgroup: WorkgroupArc,
+    scheduler: SchedulerArc,
+    maintenance: Arc<MaintenanceConfig>,
+    /// Test results are only collected from then on
+    started: SystemTime,
+    /// Shared by the decoders of all FastCGI connections
+    fcgi_stats: Arc<DecoderStats>
 }
 
 struct HandlerError
@@ -196,8 +99,7 @@ impl HandlerError
 {
     fn new(msg: &str) -> HandlerError
     {
-        HandlerError{msg: msg.to_string(),
-                     src: None}
+        HandlerError{msg: msg.to_string(), src: None}
     }
 
     fn from_error<E>(err: E, msg: &str) -> HandlerError
@@ -236,305 +138,2182 @@ impl fmt::Debug for HandlerError
     }
 }
 
-fn device_to_json(dev: &DeviceState) -> json::Value
+fn device_to_json(dev: &DeviceState, links: &Links) -> json::Value
 {
+    let address = &dev.address;
     json::json!({"description": dev.description,
-                 "address": dev.address,
-                 "level": dev.intensity})
+                 "address": address.device,
+                 "helvar_address": address.to_string(),
+                 "level": dev.intensity,
+                 "measurement": dev.measurement.as_ref().map(|m| json!({
+                     "value": m.value,
+                     "unit": m.unit,
+                     "updated": unix_time(m.updated)
+                 })),
+                 "state": dev.health.state.map(|s| s.0),
+                 "faults": health_to_json(&dev.health),
+                 "emergency": dev.emergency.as_ref()
+                     .map(|em| emergency_to_json(address, em, links)),
+                 "power": dev.power.as_ref().map(power_to_json),
+                 "running_hours": dev.running_hours.as_ref()
+                     .map(running_hours_to_json),
+                 "links": {
+                     "self": links.device(address),
+                     "subnet": links.subnet(address.cluster, address.router,
+                                            address.subnet),
+                     "level": links.device_level(address),
+                     "scenes": links.scenes(&HelvarAddress::Device(*address)),
+                     "emergency": links.emergency(&HelvarAddress::Device(*address)),
+                     "energy": links.energy(&HelvarAddress::Device(*address))
+                 }})
+}
+
+fn health_to_json(health: &DeviceHealth) -> json::Value
+{
+    let mut faults = json::Map::new();
+    for fault in Fault::ALL.iter() {
+        let fs = health.fault(*fault);
+        faults.insert(fault.as_str().to_string(),
+                      json!({"active": fs.active,
+                             "changed": fs.changed.map(unix_time)}));
+    }
+    json::Value::Object(faults)
+}
+
+/// Devices that currently have a fault
+fn faults_to_json(state: &WorkgroupState, links: &Links) -> json::Value
+{
+    let mut dev_map = json::map::Map::new();
+    for rs in state.routers.values() {
+        for sn in rs.subnets.iter().filter_map(|x| x.as_ref()) {
+            for dev in sn.devices.iter()
+                .filter_map(|x| x.as_ref()).filter(|d| d.health.has_fault())
+            {
+                let faults = dev.health.active_faults();
+                let since = faults.iter()
+                    .filter_map(|f| dev.health.fault(*f).changed).min();
+                let names: Vec<&str> = faults.iter().map(|f| f.as_str())
+                    .collect();
+                dev_map.insert(dev.address.to_string(),
+                               json!({"description": dev.description,
+                                      "faults": names,
+                                      "since": since.map(unix_time),
+                                      "checked": dev.health.checked
+                                          .map(unix_time),
+                                      "links": {
+                                          "device": links.device(&dev.address)
+                                      }}));
+            }
+        }
+    }
+    json!({"count": dev_map.len(),
+           "devices": dev_map,
+           "links": {
+               "self": links.faults(),
+               "root": links.root()
+           }})
 }
 
-fn subnet_to_json(sn: &SubnetState) -> json::Value
+fn subnet_to_json(sn: &SubnetState, cluster: u8, router: u8, links: &Links)
+                  -> json::Value
 {
     let mut dev_map = json::map::Map::new();
-    let mut dev_iter = (1..64).map(|x| &sn.devices[x-1])
-        .filter_map(|x| x.as_ref())
-        .peekable();
-    while let Some(dev) = dev_iter.next() {
-        dev_map.insert(dev.address.to_string(), 
-                       device_to_json(dev));
+    let mut dev_links = Vec::new();
+    for dev in sn.devices.iter().filter_map(|x| x.as_ref()) {
+        dev_map.insert(dev.address.device.to_string(),
+                       device_to_json(dev, links));
+        dev_links.push(json!(links.device(&dev.address)));
     }
     
     json::json!({"devices": json!(dev_map),
-                 "index": json!(sn.index)})
+                 "index": json!(sn.index),
+                 "links": {
+                     "self": links.subnet(cluster, router, sn.index),
+                     "router": links.router(cluster, router),
+                     "root": links.root(),
+                     "devices": dev_links
+                 }})
 }
 
+fn router_to_json(rs: &RouterState, cluster: u8, router: u8,
+                  control: Option<&Router>, links: &Links) -> json::Value
+{
+    let mut subnet_map = json::map::Map::new();
+    let mut subnet_links = Vec::new();
+    for sn in rs.subnets.iter().filter_map(|x| x.as_ref()) {
+        subnet_map.insert(sn.index.to_string(),
+                          subnet_to_json(sn, cluster, router, links));
+        subnet_links.push(json!(links.subnet(cluster, router, sn.index)));
+    }
+    let (address, connection) = match control {
+        Some(control) => (json!(control.address().to_string()),
+                          connection_to_json(control.transport(),
+                                             &control.connection_status())),
+        None => (json::Value::Null, json::Value::Null)
+    };
+    json!({"cluster": cluster,
+           "router": router,
+           "address": address,
+           "connection": connection,
+           "subnets": json!(subnet_map),
+           "links": {
+               "self": links.router(cluster, router),
+               "root": links.root(),
+               "clock": links.clock(cluster, router),
+               "subnets": subnet_links
+           }})
+}
 
-#[async_trait]
-impl RequestHandler for Handler 
+fn connection_to_json(transport: TransportKind, status: &ConnectionStatus)
+                      -> json::Value
 {
-    async fn handle(&mut self, req: &Request) -> Result<String, Box<dyn std::error::Error + Send>>
-    {
-        let mut subnet_arg = None::<u32>;
-        let mut address_arg = None::<u32>;
-        if let Some(path) = req.params.get("PATH_INFO") {
-            let mut parts = path.split("/");
-            let subnet_str =
-                parts.next()
-                .and_then(|p| if p.is_empty() {None} else {Some(p)})
-                .or_else(|| parts.next());
-            if let Some(subnet_str) = subnet_str {
-                subnet_arg = match u32::from_str(subnet_str)
-                {
-                    Ok(i) => Some(i),
-                    Err(e) => return Err(Box::new(HandlerError::from_error(
-                        e, "Failed to parse subnet index")))
-                };
-                let address_str = parts.next();
-                if let Some(address_str) = address_str {
-                    address_arg = match u32::from_str(address_str)
-                    {
-                        Ok(i) => Some(i),
-                        Err(e) => return Err(Box::new(HandlerError::from_error(
-                            e,"Failed to parse address")))
-                    };
-                }
-            }
-                    
-        }
-	
+    json::json!({"transport": transport.to_string(),
+                 "state": status.state.as_str(),
+                 "since": unix_time(status.since),
+                 "last_error": status.last_error})
+}
 
-        if let Some(query_str) = req.params.get("QUERY_STRING") {
-            if query_str.starts_with("level=") {
-                match u8::from_str(&query_str[6..]) {
-                    Ok(level) => {
-                        if let (Some(sn_index), Some(addr)) =
-                            (subnet_arg, address_arg)
-                        {
-                            // println!("Set level {}.{}: {}",sn_index, addr, level);
-                            let mut router = self.router_control.lock().await;
-                            match router.set_direct_level_device(
-                                sn_index.try_into().unwrap(),
-                                addr.try_into().unwrap(),
-                                level.into(), 70).await {
-                                Ok(_) => {},
-                                Err(e) => {
-                                    return Err(Box::new(
-                                        HandlerError::from_error(
-                                            e,"Failed to set device level")))
-                                }
-                            }
-                            let mut rs = self.router_state.lock().unwrap();
-                            if let Some(dev) = rs.get_device_mut(sn_index, addr) {
-                                dev.intensity = level;
-                            }
+fn workgroup_to_json(workgroup: &Workgroup, links: &Links) -> json::Value
+{
+    let mut cluster_map = json::map::Map::new();
+    for cluster in &workgroup.clusters {
+        let addresses: Vec<String> = cluster.routers.iter()
+            .map(|&r| workgroup.router_address(cluster.cluster, r).to_string())
+            .collect();
+        cluster_map.insert(cluster.cluster.to_string(),
+                           json!({"routers": cluster.routers,
+                                  "addresses": addresses}));
+    }
+    let routers: Vec<String> = workgroup.router_addresses().iter()
+        .map(|a| a.to_string()).collect();
+    json!({"seed": workgroup.seed.to_string(),
+           "discovered": unix_time(workgroup.discovered),
+           "clusters": cluster_map,
+           "routers": routers,
+           "links": {
+               "self": links.workgroup(),
+               "root": links.root()
+           }})
+}
 
-                        }
-                    }
-                    Err(e) => return Err(Box::new(
-                        HandlerError::from_error(
-                            e,"Failed to parse level")))
+fn group_to_json(group: &GroupState, links: &Links) -> json::Value
+{
+    let scene = group.scene.map(|(block, scene)| {
+        json!({"block": block, "scene": scene})
+    });
+    json!({"group": group.group,
+           "description": group.description,
+           "level": group.level,
+           "proportion": group.proportion,
+           "scene": scene,
+           "power": group.power.as_ref().map(power_to_json),
+           "links": {
+               "self": links.group(group.group),
+               "groups": links.groups(),
+               "level": links.group_level(group.group),
+               "proportion": links.group_proportion(group.group),
+               "scene": links.group_scene(group.group),
+               "scenes": links.scenes(&HelvarAddress::Group(group.group)),
+               "emergency": links.emergency(&HelvarAddress::Group(group.group)),
+               "energy": links.energy(&HelvarAddress::Group(group.group))
+           }})
+}
+
+fn unix_time(time: std::time::SystemTime) -> u64
+{
+    time.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn power_to_json(meter: &EnergyMeter) -> json::Value
+{
+    let current = meter.current();
+    let today = dates::local_day(SystemTime::now());
+    json!({"watts": current.map(|s| s.watts),
+           "time": current.map(|s| unix_time(s.time)),
+           "kwh_today": meter.today_wh(today) / 1000.0,
+           "kwh_total": meter.total_wh / 1000.0,
+           "since": meter.since.map(unix_time)})
+}
+
+fn clock_check_to_json(check: &ClockCheck) -> json::Value
+{
+    json!({"drift": check.drift,
+           "checked": unix_time(check.checked),
+           "synced": check.synced})
+}
+
+fn running_hours_to_json(hours: &RunningHours) -> json::Value
+{
+    json!({"lamp": hours.lamp,
+           "ballast": hours.ballast,
+           "updated": hours.updated.map(unix_time)})
+}
+
+fn replacement_list_to_json(list: &ReplacementList, links: &Links)
+                            -> json::Value
+{
+    let percent = |v: Option<f64>| v.map(|v| (v * 1000.0).round() / 10.0);
+    let devices: Vec<json::Value> = list.rows.iter().map(|row| {
+        json!({"address": row.address.to_string(),
+               "description": row.description,
+               "device_type": row.device_type,
+               "lamp": {
+                   "hours": row.lamp_hours,
+                   "rated": row.rated.lamp,
+                   "used_percent": percent(row.lamp_used)
+               },
+               "ballast": {
+                   "hours": row.ballast_hours,
+                   "rated": row.rated.ballast,
+                   "used_percent": percent(row.ballast_used)
+               },
+               "links": {"device": links.device(&row.address)}})
+    }).collect();
+    json!({"threshold_percent": percent(Some(list.threshold)),
+           "count": devices.len(),
+           "devices": devices,
+           "links": {
+               "self": links.maintenance(),
+               "csv": links.maintenance_csv(),
+               "root": links.root()
+           }})
+}
+
+/// Power readings of a device or group as [time, watts] pairs
+fn energy_to_json(target: &HelvarAddress, meter: Option<&EnergyMeter>,
+                  from: Option<SystemTime>, to: Option<SystemTime>,
+                  links: &Links) -> json::Value
+{
+    let samples: Vec<json::Value> = match meter {
+        Some(meter) => meter.samples(from, to)
+            .map(|s| json!([unix_time(s.time), s.watts])).collect(),
+        None => Vec::new()
+    };
+    json!({"power": meter.map(power_to_json),
+           "samples": samples,
+           "links": {
+               "self": links.energy(target),
+               "target": links.target(target),
+               "overview": links.energy_overview()
+           }})
+}
+
+/// Power and energy of all polled devices and groups. The totals are
+/// of the devices only, as groups contain devices.
+fn energy_overview_to_json(state: &WorkgroupState, links: &Links)
+                           -> json::Value
+{
+    let today = dates::local_day(SystemTime::now());
+    let mut dev_map = json::map::Map::new();
+    let (mut watts, mut today_wh) = (0.0, 0.0);
+    for rs in state.routers.values() {
+        for sn in rs.subnets.iter().filter_map(|x| x.as_ref()) {
+            for dev in sn.devices.iter().filter_map(|x| x.as_ref()) {
+                if let Some(meter) = &dev.power {
+                    watts += meter.current().map_or(0.0, |s| s.watts);
+                    today_wh += meter.today_wh(today);
+                    let target = HelvarAddress::Device(dev.address);
+                    let mut obj = power_to_json(meter);
+                    obj["links"] = json!({"energy": links.energy(&target)});
+                    dev_map.insert(dev.address.to_string(), obj);
                 }
             }
         }
-        let rs = self.router_state.lock().unwrap();
-        let mut reply = "Content-type: application/json\r\n\r\n".to_string();
+    }
+    let mut group_map = json::map::Map::new();
+    for gs in state.groups.values() {
+        if let Some(meter) = &gs.power {
+            let target = HelvarAddress::Group(gs.group);
+            let mut obj = power_to_json(meter);
+            obj["links"] = json!({"energy": links.energy(&target)});
+            group_map.insert(gs.group.to_string(), obj);
+        }
+    }
+    json!({"watts": watts,
+           "kwh_today": today_wh / 1000.0,
+           "devices": dev_map,
+           "groups": group_map,
+           "links": {
+               "self": links.energy_overview(),
+               "root": links.root()
+           }})
+}
 
-        let top_obj = match (subnet_arg, address_arg) {
-            (Some(subnet), Some(addr)) => {
-                if let Some(dev) = rs.get_device(subnet, addr) {
-                    device_to_json(dev)
-                } else {
-                    json::Value::Null
-                }
-            },
-            (Some(subnet), None) => {
-                if let Some(sn) = rs.get_subnet(subnet) {
-                    subnet_to_json(&sn)
-                } else {
-                    json::Value::Null
-                } 
-            },
-            (None, _) => {
-                let mut subnet_map = serde_json::map::Map::new();
-                
-                let mut sn_iter = 
-                    rs.subnets.iter()
-                    .filter_map(|x| x.as_ref())
-                    .peekable();
-                while let Some(sn) = sn_iter.next() {
-                    
-            
-                    subnet_map.insert(sn.index.to_string(), 
-                                      subnet_to_json(sn));
-                    
+fn test_status_to_json(status: &TestStatus) -> json::Value
+{
+    json!({"state": status.state.map(|s| s.as_str()),
+           "code": status.state.map(|s| s.0),
+           "test_time": status.test_time,
+           "in_progress": status.in_progress,
+           "requested": status.requested.map(unix_time)})
+}
+
+/// URLs for the emergency commands of a device or group
+fn emergency_action_links(target: &HelvarAddress, links: &Links)
+                          -> json::Map<String, json::Value>
+{
+    let url = links.emergency(target);
+    let mut map = json::Map::new();
+    for action in &[EmergencyAction::Start(TestKind::Function),
+                    EmergencyAction::Start(TestKind::Duration),
+                    EmergencyAction::Stop, EmergencyAction::Reset] {
+        map.insert(action.as_str().to_string(),
+                   json!(format!("{}?action={}", url, action.as_str())));
+    }
+    map.insert("self".to_string(), json!(url));
+    map.insert("target".to_string(), json!(links.target(target)));
+    map
+}
+
+fn emergency_to_json(address: &DeviceAddress, em: &EmergencyState,
+                     links: &Links) -> json::Value
+{
+    let results: Vec<json::Value> = em.results.iter().map(|r| {
+        json!({"test": r.kind.as_str(),
+               "state": r.state.as_str(),
+               "code": r.state.0,
+               "passed": r.state.passed(),
+               "test_time": r.test_time,
+               "recorded": unix_time(r.recorded)})
+    }).collect();
+    json!({"helvar_address": address.to_string(),
+           "function": test_status_to_json(&em.function),
+           "duration": test_status_to_json(&em.duration),
+           "test_in_progress": em.test_in_progress(),
+           "battery_charge": em.battery_charge,
+           "battery_failure": em.battery_failure,
+           "battery_time": em.battery_time,
+           "total_lamp_time": em.total_lamp_time,
+           "updated": em.updated.map(unix_time),
+           "results": results,
+           "links": emergency_action_links(&HelvarAddress::Device(*address),
+                                           links)})
+}
+
+fn scene_to_json(target: &HelvarAddress, block: &SceneBlock, scene: u8,
+                 links: &Links) -> json::Value
+{
+    let info: Option<&SceneInfo> = block.scenes.get(&scene);
+    let url = links.scene(target, block.block, scene);
+    json!({"block": block.block,
+           "scene": scene,
+           "level": info.and_then(|i| i.level),
+           "stored": info.map(|i| unix_time(i.stored)),
+           "last_recalled": block.last_recalled == Some(scene),
+           "links": {
+               "self": url,
+               "block": links.scene_block(target, block.block),
+               "recall": format!("{}?action=recall", url),
+               "store": format!("{}?action=store&level={{level}}", url),
+               "store_as": format!("{}?action=store_as", url)
+           }})
+}
+
+fn scene_block_to_json(target: &HelvarAddress, block: &SceneBlock,
+                       links: &Links) -> json::Value
+{
+    let mut scene_map = json::map::Map::new();
+    for &scene in block.scenes.keys() {
+        scene_map.insert(scene.to_string(),
+                         scene_to_json(target, block, scene, links));
+    }
+    let scene_links: Vec<String> = address::SCENES
+        .map(|scene| links.scene(target, block.block, scene)).collect();
+    json!({"block": block.block,
+           "last_recalled": block.last_recalled,
+           "recalled": block.recalled.map(unix_time),
+           "scenes": scene_map,
+           "links": {
+               "self": links.scene_block(target, block.block),
+               "scenes": scene_links
+           }})
+}
+
+fn scenes_to_json(target: &HelvarAddress, blocks: Option<&SceneBlocks>,
+                  links: &Links) -> json::Value
+{
+    let mut block_map = json::map::Map::new();
+    for block in blocks.into_iter().flat_map(|b| b.values()) {
+        block_map.insert(block.block.to_string(),
+                         scene_block_to_json(target, block, links));
+    }
+    let block_links: Vec<String> = address::BLOCKS
+        .map(|block| links.scene_block(target, block)).collect();
+    json!({"blocks": block_map,
+           "links": {
+               "self": links.scenes(target),
+               "target": links.target(target),
+               "blocks": block_links
+           }})
+}
+
+fn scheduled_test_to_json(test: &Option<ScheduledTest>) -> json::Value
+{
+    match test {
+        Some(test) => json!({
+            "schedule": test.schedule.to_string(),
+            "next": test.next.map(unix_time),
+            "started": test.started.map(unix_time),
+            "finished": test.finished.map(unix_time),
+            "batch": test.batch.map(|(batch, _)| batch),
+            "batches": test.batch.map(|(_, count)| count),
+            "tested": test.tested,
+            "skipped": test.skipped
+        }),
+        None => json::Value::Null
+    }
+}
+
+/// Emergency luminaires of all routers
+fn emergency_devices_to_json(state: &WorkgroupState,
+                             scheduler: &TestScheduler, links: &Links)
+                             -> json::Value
+{
+    let mut dev_map = json::map::Map::new();
+    for rs in state.routers.values() {
+        for sn in rs.subnets.iter().filter_map(|x| x.as_ref()) {
+            for dev in sn.devices.iter().filter_map(|x| x.as_ref()) {
+                if let Some(em) = &dev.emergency {
+                    dev_map.insert(dev.address.to_string(),
+                                   emergency_to_json(&dev.address, em, links));
                 }
-                json!({"subnets": json!(subnet_map)})
             }
-        };
-        reply += &serde_json::to_string_pretty(&top_obj).unwrap();
-        Ok(reply)
+        }
     }
+    json!({"devices": dev_map,
+           "schedule": {
+               "function": scheduled_test_to_json(&scheduler.function),
+               "duration": scheduled_test_to_json(&scheduler.duration),
+               "batches": scheduler.batches
+           },
+           "links": {
+               "self": links.emergency_devices(),
+               "root": links.root(),
+               "report_csv": links.emergency_report("csv"),
+               "report_html": links.emergency_report("html")
+           }})
 }
 
-type RouterStateArc = Arc<StdMutex<RouterState>>;
-type RouterArc = Arc<Mutex<Router>>;
+/// Fade time used when the request doesn't give one, in 1/100 s
+const DEFAULT_FADE: u32 = 70;
 
-async fn connection_handler<S>(stream: Arc<Mutex<Box<S>>>, 
-                               router_state: RouterStateArc,
-                               router_control: RouterArc)
-    where S: AsyncRead+AsyncWrite+Unpin+Send+'static
+fn parse_param<T>(params: &BTreeMap<String, String>, name: &str)
+                  -> Result<Option<T>, HandlerError>
+    where T: FromStr, T::Err: std::error::Error + Send + 'static
 {
-    let rec_stream = RecordInputStream::new(stream.clone());
-    let rec_output = RecordOutput::new(stream);
-    let mut decoder = Decoder::new();
-    decoder.run(rec_stream,rec_output, 
-                &mut Handler{router_state, router_control}).await;
+    match params.get(name) {
+        Some(value) => match T::from_str(value) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => Err(HandlerError::from_error(
+                e, &format!("Failed to parse {}", name)))
+        },
+        None => Ok(None)
+    }
 }
 
-async fn query_device(router: &mut Router, router_state: &RouterStateArc,
-                      subnet: u8, addr: u8, priority: u32)
-                      -> Result<(), Box<dyn std::error::Error>>
+/// Parse a scene given as "block.scene"
+fn parse_scene(s: &str) -> Result<(u8, u8), HandlerError>
 {
-    let mut dev = {
-        let rs = router_state.lock().unwrap();
-        rs.subnets.get(usize::from(subnet) -1).and_then({
-            |sn| match sn {
-                Some(sn) => sn.devices[usize::from(addr)-1].clone(),
-                None => None
+    let mut parts = s.splitn(2, '.');
+    let block = parts.next().and_then(|b| u8::from_str(b).ok());
+    let scene = parts.next().and_then(|s| u8::from_str(s).ok());
+    match (block, scene) {
+        (Some(block), Some(scene)) => Ok((block, scene)),
+        _ => Err(HandlerError::new("Scene must be given as block.scene"))
+    }
+}
+
+impl Handler
+{
+    /// A router for commands to groups
+    fn any_router(&self) -> Result<Router, HandlerError>
+    {
+        match self.routers.lock().unwrap().any_router() {
+            Some(c) => Ok(c),
+            None => Err(HandlerError::new("No router"))
+        }
+    }
+
+    /// The router a device is connected to
+    fn device_router(&self, address: &DeviceAddress)
+                     -> Result<Router, HandlerError>
+    {
+        self.router(address.cluster, address.router)
+    }
+
+    fn router(&self, cluster: u8, router: u8) -> Result<Router, HandlerError>
+    {
+        match self.routers.lock().unwrap().routers.get(&(cluster, router)) {
+            Some(c) => Ok(c.clone()),
+            None => Err(HandlerError::new("No such router"))
+        }
+    }
+
+    /// /{cluster}/{router}/clock. Any of `time` (seconds since the Unix
+    /// epoch, or "now" for the host time), `longitude` and `latitude`
+    /// (degrees), `timezone` (seconds from UTC) and `daylight_saving`
+    /// (true or false) given are set. With `action=reset` the router
+    /// first goes back to the default time and location.
+    async fn handle_clock(&self, cluster: u8, router: u8,
+                          params: &BTreeMap<String, String>, links: &Links)
+                          -> Result<json::Value, HandlerError>
+    {
+        let control = self.router(cluster, router)?;
+        let now = params.get("time").is_some_and(|t| t == "now");
+        let time = if now {
+            None
+        } else {
+            parse_param::<i64>(params, "time")?
+                .map(|unix_time| RouterTime{unix_time})
+        };
+        let coordinate = |name: &str, limit: f64| {
+            match parse_param::<f64>(params, name)? {
+                Some(d) if d.abs() <= limit =>
+                    Ok(Some(Coordinate::from_degrees(d))),
+                Some(_) => Err(HandlerError::new(&format!(
+                    "The {} must be -{} to {} degrees", name, limit, limit))),
+                None => Ok(None)
             }
-        }).unwrap_or_else(|| Box::new(DeviceState::new()))
-    };
-    dev.address = u32::from(addr);
-    'done: loop {
-        match router.query_device_type(subnet,addr).await {
-            Ok(dtype) => {
-                dev.device_type = dtype;
+        };
+        let longitude = coordinate("longitude", 180.0)?;
+        let latitude = coordinate("latitude", 90.0)?;
+        let time_zone = parse_param::<i32>(params, "timezone")?;
+        if time_zone.is_some_and(|tz| !(-12 * 3600..=14 * 3600).contains(&tz)) {
+            return Err(HandlerError::new(
+                "The time zone must be -43200 to 50400 seconds"));
+        }
+        let dst = parse_param::<bool>(params, "daylight_saving")?;
+        let reset = match params.get("action").map(|a| a.as_str()) {
+            Some("reset") => true,
+            Some(a) => return Err(HandlerError::new(
+                &format!("Unknown clock action \"{}\"", a))),
+            None => false
+        };
+
+        let failed = |e| HandlerError::from_error(e, "Failed to set the clock");
+        if reset {
+            control.reset_time_and_location().await.map_err(failed)?;
+        }
+        if now {
+            control.set_time(RouterTime::from_system_time(SystemTime::now()))
+                .await.map_err(failed)?;
+        }
+        if let Some(time) = time {
+            control.set_time(time).await.map_err(failed)?;
+        }
+        if let Some(longitude) = longitude {
+            control.set_longitude(longitude).await.map_err(failed)?;
+        }
+        if let Some(latitude) = latitude {
+            control.set_latitude(latitude).await.map_err(failed)?;
+        }
+        if let Some(seconds) = time_zone {
+            control.set_time_zone(TimeZoneOffset{seconds}).await
+                .map_err(failed)?;
+        }
+        if let Some(enabled) = dst {
+            control.set_daylight_saving(DaylightSaving{enabled}).await
+                .map_err(failed)?;
+        }
+
+        let time = match control.query_time().await {
+            Ok(time) => time,
+            Err(e) => return Err(HandlerError::from_error(
+                e, "Failed to query the router time"))
+        };
+        let drift = time.drift(SystemTime::now());
+        let longitude = control.query_longitude().await.ok();
+        let latitude = control.query_latitude().await.ok();
+        let time_zone = control.query_time_zone().await.ok();
+        let dst = control.query_daylight_saving().await.ok();
+        let state = self.state.lock().unwrap();
+        let last_check = state.get_router(cluster, router)
+            .and_then(|rs| rs.clock.as_ref()).map(clock_check_to_json);
+        Ok(json!({"time": time.unix_time,
+                  "drift": drift,
+                  "longitude": longitude.map(|c| c.degrees()),
+                  "latitude": latitude.map(|c| c.degrees()),
+                  "timezone": time_zone.map(|tz| tz.seconds),
+                  "daylight_saving": dst.map(|d| d.enabled),
+                  "last_check": last_check,
+                  "links": {
+                      "self": links.clock(cluster, router),
+                      "router": links.router(cluster, router)
+                  }}))
+    }
+
+    /// .../scenes, .../scenes/{block} and .../scenes/{block}/{scene} of
+    /// a device or group. A scene is recalled or stored with the
+    /// `action` parameter.
+    async fn handle_scenes(&self, target: &HelvarAddress, parts: &[&str],
+                           params: &BTreeMap<String, String>, links: &Links,
+                           control: &Router)
+                           -> Result<json::Value, HandlerError>
+    {
+        if parts.len() > 2 {
+            return Err(HandlerError::new("Path too long"));
+        }
+        let block = match parts.first() {
+            Some(b) => match address::parse_block(b) {
+                Ok(b) => b,
+                Err(e) => return Err(HandlerError::from_error(
+                    e, "Failed to parse block"))
             },
-            Err(HelvarError::NoSuchDevice) => return Ok(()),
-            Err(e) => {
-                return Err(WrapperError::new("Failed to query device type",
-                                             Box::new(e)).into());
+            None => {
+                let state = self.state.lock().unwrap();
+                return Ok(scenes_to_json(target, state.get_scenes(target),
+                                         links));
             }
-        }
-        if HelvarDeviceType::from(dev.device_type).is_load() { 
-            match router.query_load_level(subnet,addr).await {
-                Ok(level) => {
-                    dev.intensity = u8::try_from(level).unwrap_or(0xff);
-                },
-                Err(HelvarError::NoSuchDevice) => return Ok(()),
-                Err(e) => {
-                    return Err(WrapperError::new("Failed to query load level",
-                                                 Box::new(e)).into());
+        };
+        let scene = match parts.get(1) {
+            Some(s) => match address::parse_scene(s) {
+                Ok(s) => s,
+                Err(e) => return Err(HandlerError::from_error(
+                    e, "Failed to parse scene"))
+            },
+            None => {
+                if let HelvarAddress::Group(group) = target {
+                    match control.query_last_scene_in_block(*group,
+                                                            block).await {
+                        Ok(last) => if let Ok(last) = u8::try_from(last) {
+                            let mut state = self.state.lock().unwrap();
+                            state.scene_block_mut(target, block)
+                                .last_recalled = Some(last);
+                        },
+                        Err(e) => eprintln!(
+                            "Failed to query last scene in block {} of {}: {}",
+                            block, target, e)
+                    }
                 }
+                let state = self.state.lock().unwrap();
+                return Ok(match state.get_scenes(target)
+                          .and_then(|s| s.get(&block)) {
+                    Some(sb) => scene_block_to_json(target, sb, links),
+                    None => scene_block_to_json(target, &SceneBlock::new(block),
+                                                links)
+                });
             }
+        };
+        let fade = parse_param::<u32>(params, "fade")?.unwrap_or(DEFAULT_FADE);
+        let force = parse_param::<u8>(params, "force")?.is_some_and(|f| f != 0);
+        let level = parse_param::<u8>(params, "level")?;
+        if level.is_some_and(|l| l > 100) {
+            return Err(HandlerError::new("Level must be 0 to 100"));
         }
-        if priority > 1 {break 'done}
-        match router.query_device_description(subnet,addr).await {
-            Ok(descr) => {
-                dev.description = descr;
-            },
-            Err(e) => {
-                return Err(WrapperError::new("Failed to query device description",
-                                             Box::new(e)).into());
-            }
+        let action = params.get("action").map(|a| a.as_str());
+        let res = match (action, target) {
+            (None, _) => Ok(()),
+            (Some("recall"), HelvarAddress::Group(g)) =>
+                control.recall_scene_group(*g, block, scene, fade).await,
+            (Some("recall"), HelvarAddress::Device(a)) =>
+                control.recall_scene_device(a, block, scene, fade).await,
+            (Some("store"), _) if level.is_none() =>
+                return Err(HandlerError::new("Storing a scene needs a level")),
+            (Some("store"), HelvarAddress::Group(g)) =>
+                control.store_scene_group(*g, block, scene,
+                                          level.unwrap_or(0), force).await,
+            (Some("store"), HelvarAddress::Device(a)) =>
+                control.store_scene_device(a, block, scene,
+                                           level.unwrap_or(0), force).await,
+            (Some("store_as"), HelvarAddress::Group(g)) =>
+                control.store_as_scene_group(*g, block, scene, force).await,
+            (Some("store_as"), HelvarAddress::Device(a)) =>
+                control.store_as_scene_device(a, block, scene, force).await,
+            (Some(_), _) => return Err(HandlerError::new(
+                "Action must be recall, store or store_as"))
+        };
+        if let Err(e) = res {
+            return Err(HandlerError::from_error(e, "Scene command failed"));
+        }
+        let mut state = self.state.lock().unwrap();
+        let sb = state.scene_block_mut(target, block);
+        match action {
+            Some("recall") => sb.recall(scene),
+            Some("store") => sb.store(scene, level),
+            Some("store_as") => sb.store(scene, None),
+            _ => {}
         }
-        break
+        Ok(scene_to_json(target, sb, scene, links))
     }
-    
-    let mut rs = router_state.lock().unwrap();
-    while rs.subnets.len() <= subnet.into() {
-        rs.subnets.push(None);
-    }
-    let sn = rs.subnets[usize::from(subnet) -1]
-        .get_or_insert_with(
-            || Box::new(SubnetState::new(u32::from(subnet))));
-    sn.devices[usize::from(addr)-1] = Some(dev);
-    
-    Ok(())
-}
 
-async fn fcgi_task(router: RouterArc, router_state:RouterStateArc)
-{
-    let std_listener = unsafe {
-        std::os::unix::net::UnixListener::from_raw_fd(0)
-    };
-    let mut listener = 
-        tokio::net::UnixListener::from_std(std_listener).unwrap();
-    let mut incoming = listener.incoming();
-    
-    //println!("Listening");
-    while let Some(stream) = incoming.next().await {
-        match stream {
-            Ok(stream) => {
-                
-                let io = Arc::new(Mutex::new(Box::new(stream)));
-                tokio::spawn(connection_handler(io,
-                                                router_state.clone(),
-                                                router.clone()));
-            },
-            Err(e) => {
-                println!("Error: {:?}", e);
-            }
+    /// Compliance report of the emergency luminaires. The period is
+    /// given by the dates `from` and `to`, both included. Dates start
+    /// and end at local midnight, like the test schedules.
+    fn emergency_report(&self, params: &BTreeMap<String, String>)
+                        -> Result<String, HandlerError>
+    {
+        let date = |name: &str| match params.get(name) {
+            Some(d) => dates::parse_date(d).map(Some)
+                .map_err(|e| HandlerError::new(&e)),
+            None => Ok(None)
+        };
+        let from = date("from")?.map(dates::local_midnight);
+        let to = date("to")?.map(|d| dates::local_midnight(d + 1));
+        let report = Report::new(&self.state.lock().unwrap(), self.started,
+                                 from, to);
+        match params.get("format").map_or("html", |f| f.as_str()) {
+            "csv" => Ok(format!(
+                "Content-type: text/csv; charset=utf-8\r\n\
+                 Content-Disposition: attachment; \
+                 filename=\"emergency-report-{}.csv\"\r\n\r\n{}",
+                dates::format_date(report.generated), report.to_csv())),
+            "html" => Ok("Content-type: text/html; charset=utf-8\r\n\r\n"
+                         .to_string() + &report.to_html()),
+            _ => Err(HandlerError::new("Format must be csv or html"))
         }
     }
-    //println!("Stopped");
-}
 
-async fn router_poll_task(router: RouterArc, router_state:RouterStateArc)
-{
-      
-        for subnet in 1..=2 {
-            for a in 1..=64 {
-                let mut router = router.lock().await;
-                match query_device(&mut router, &router_state,
-                                   subnet, a, 0).await
-                {
-                    Ok(()) => {},
-                    Err(e) => eprintln!("Initial query failed: {}",e)
-                }
+    /// .../emergency of a device or group. Tests are started and stopped
+    /// with the `action` parameter.
+    async fn handle_emergency(&self, target: &HelvarAddress,
+                              params: &BTreeMap<String, String>,
+                              links: &Links, control: &Router)
+                              -> Result<json::Value, HandlerError>
+    {
+        let action = match params.get("action") {
+            Some(a) => match EmergencyAction::from_str(a) {
+                Ok(a) => Some(a),
+                Err(e) => return Err(HandlerError::new(&e))
+            },
+            None => None
+        };
+        if let Some(action) = action {
+            if let Err(e) = control.emergency_action(target, action).await {
+                return Err(HandlerError::from_error(
+                    e, "Emergency command failed"));
             }
         }
-        {
-            //let rs = router_state.lock();
-            //println!("{:?}", rs);
-        }
-        loop {
-            for subnet in 1..=2 {
-                for a in 1..=64 {
-                    {
-                        let mut router = router.lock().await;
-                        match query_device(&mut router, &router_state,
-                                           subnet, a, 0).await
-                        {
-                            Ok(()) => {},
-                            Err(e) => eprintln!("Update query failed: {}",e)
-                        }
-                    }
-                    tokio::time::delay_for(Duration::from_secs(1)).await;
-                    //println!("Updating {}",a);
+        let mut state = self.state.lock().unwrap();
+        match target {
+            HelvarAddress::Group(group) => {
+                let gs = state.group_mut(*group);
+                if let Some(action) = action {
+                    gs.emergency_action = Some((action, SystemTime::now()));
+                }
+                let last_action = gs.emergency_action.map(|(a, time)| {
+                    json!({"action": a.as_str(), "time": unix_time(time)})
+                });
+                Ok(json!({"group": group,
+                          "last_action": last_action,
+                          "links": emergency_action_links(target, links)}))
+            },
+            HelvarAddress::Device(address) => {
+                let dev = match state.get_device_mut(address) {
+                    Some(dev) => dev,
+                    None => return Ok(json::Value::Null)
+                };
+                let em = dev.emergency.get_or_insert_with(
+                    EmergencyState::default);
+                if let Some(EmergencyAction::Start(kind)) = action {
+                    em.test_mut(kind).requested = Some(SystemTime::now());
                 }
+                Ok(emergency_to_json(address, em, links))
             }
         }
-}
+    }
 
-#[tokio::main]
-async fn main() {
-    
-    let addr_str = match env::var("ROUTER_ADDRESS")
+    /// Devices near the end of their rated life, as JSON or CSV. The
+    /// `threshold` parameter overrides the configured percentage.
+    fn maintenance(&self, params: &BTreeMap<String, String>, links: &Links)
+                   -> Result<String, HandlerError>
     {
-        Ok(a) => a,
-        Err(_) => {
-            eprintln!("No environment variable ROUTER_ADDRESS found");
-            return;
-        }
-    };
-    let addr = match Ipv4Addr::from_str(&addr_str) {
-        Ok(a) => a,
-        Err(e) => {
-            eprintln!("Invalid router address: {}", e);
-            return;
+        let threshold = match parse_param::<f64>(params, "threshold")? {
+            Some(t) => t / 100.0,
+            None => self.maintenance.threshold
+        };
+        let list = ReplacementList::new(&self.state.lock().unwrap(),
+                                        &self.maintenance.rated_life,
+                                        threshold);
+        match params.get("format").map_or("json", |f| f.as_str()) {
+            "csv" => Ok(format!(
+                "Content-type: text/csv; charset=utf-8\r\n\
+                 Content-Disposition: attachment; \
+                 filename=\"maintenance-{}.csv\"\r\n\r\n{}",
+                dates::format_date(SystemTime::now()), list.to_csv())),
+            "json" => Ok("Content-type: application/json\r\n\r\n".to_string()
+                         + &serde_json::to_string_pretty(
+                             &replacement_list_to_json(&list, links)).unwrap()),
+            _ => Err(HandlerError::new("Format must be csv or json"))
         }
-    };
-    let router_state = Arc::new(StdMutex::new(RouterState::new()));
-    let router = Router::connect(&addr).await.unwrap();
-    let router = Arc::new(tokio::sync::Mutex::new(router));
-    
-    let fcgi = tokio::spawn(fcgi_task(router.clone(),
-                                      router_state.clone()));
-    
-    let helvar = tokio::spawn(router_poll_task(router.clone(),
-                                               router_state.clone()));
-                              
-    fcgi.await.unwrap();
-    helvar.await.unwrap();
+    }
+
+    /// .../energy of a device or group. The readings can be limited
+    /// with `from` and `to` in seconds since the Unix epoch.
+    fn handle_energy(&self, target: &HelvarAddress,
+                     params: &BTreeMap<String, String>, links: &Links)
+                     -> Result<json::Value, HandlerError>
+    {
+        let time = |name: &str| parse_param::<u64>(params, name)
+            .map(|t| t.map(|t| UNIX_EPOCH + Duration::from_secs(t)));
+        let from = time("from")?;
+        let to = time("to")?;
+        let state = self.state.lock().unwrap();
+        let meter = match target {
+            HelvarAddress::Device(address) => state.get_device(address)
+                .and_then(|dev| dev.power.as_ref()),
+            HelvarAddress::Group(group) => state.groups.get(group)
+                .and_then(|gs| gs.power.as_ref())
+        };
+        Ok(energy_to_json(target, meter, from, to, links))
+    }
+
+    /// Set the level of a device with the `level` and `fade` parameters
+    async fn set_device_level(&self, device: Option<DeviceAddress>,
+                              params: &BTreeMap<String, String>)
+                              -> Result<(), HandlerError>
+    {
+        let level = match parse_param::<u8>(params, "level")? {
+            Some(level) => level,
+            None => return Ok(())
+        };
+        let fade = parse_param::<u32>(params, "fade")?.unwrap_or(DEFAULT_FADE);
+        if level > 100 {
+            return Err(HandlerError::new("Level must be 0 to 100"));
+        }
+        let address = match device {
+            Some(address) => address,
+            None => return Err(HandlerError::new(
+                "The level can only be set for a device or group"))
+        };
+        let control = self.device_router(&address)?;
+        if let Err(e) = control.set_direct_level_device(&address, level,
+                                                        fade).await {
+            return Err(HandlerError::from_error(
+                e, "Failed to set device level"));
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(dev) = state.get_device_mut(&address) {
+            dev.intensity = level;
+        }
+        Ok(())
+    }
+
+    /// /groups and /groups/{id}
+    async fn handle_groups(&self, parts: &[&str],
+                           params: &BTreeMap<String, String>, links: &Links)
+                           -> Result<json::Value, HandlerError>
+    {
+        let group = match parts.get(1) {
+            Some(g) => match address::parse_group(g) {
+                Ok(g) => g,
+                Err(e) => return Err(HandlerError::from_error(
+                    e, "Failed to parse group"))
+            },
+            None => {
+                let state = self.state.lock().unwrap();
+                let mut group_map = json::map::Map::new();
+                let mut group_links = Vec::new();
+                for group in state.groups.values() {
+                    group_map.insert(group.group.to_string(),
+                                     group_to_json(group, links));
+                    group_links.push(json!(links.group(group.group)));
+                }
+                return Ok(json!({"groups": group_map,
+                                 "links": {
+                                     "self": links.groups(),
+                                     "root": links.root(),
+                                     "groups": group_links
+                                 }}));
+            }
+        };
+        if parts.len() == 3 && parts[2] == "energy" {
+            return self.handle_energy(&HelvarAddress::Group(group), params,
+                                      links);
+        }
+        if parts.len() > 2 {
+            let control = self.any_router()?;
+            let target = HelvarAddress::Group(group);
+            return match parts[2] {
+                "scenes" => self.handle_scenes(&target, &parts[3..], params,
+                                               links, &control).await,
+                "emergency" if parts.len() == 3 =>
+                    self.handle_emergency(&target, params, links,
+                                          &control).await,
+                _ => Err(HandlerError::new("No such resource"))
+            };
+        }
+        let level = parse_param::<u8>(params, "level")?;
+        let proportion = parse_param::<i8>(params, "proportion")?;
+        let fade = parse_param::<u32>(params, "fade")?.unwrap_or(DEFAULT_FADE);
+        let scene = match params.get("scene") {
+            Some(s) => Some(parse_scene(s)?),
+            None => None
+        };
+        if level.is_some_and(|l| l > 100) {
+            return Err(HandlerError::new("Level must be 0 to 100"));
+        }
+        if proportion.is_some_and(|p| !(-100..=100).contains(&p)) {
+            return Err(HandlerError::new("Proportion must be -100 to 100"));
+        }
+        let control = self.any_router()?;
+        if let Some(level) = level {
+            if let Err(e) = control.set_direct_level_group(group, level,
+                                                           fade).await {
+                return Err(HandlerError::from_error(
+                    e, "Failed to set group level"));
+            }
+            let mut state = self.state.lock().unwrap();
+            let gs = state.group_mut(group);
+            gs.level = Some(level);
+            gs.proportion = None;
+            gs.scene = None;
+        }
+        if let Some(proportion) = proportion {
+            if let Err(e) = control.set_direct_proportion_group(
+                group, proportion, fade).await {
+                return Err(HandlerError::from_error(
+                    e, "Failed to set group proportion"));
+            }
+            self.state.lock().unwrap().group_mut(group).proportion =
+                Some(proportion);
+        }
+        if let Some((block, scene)) = scene {
+            if let Err(e) = control.recall_scene_group(group, block, scene,
+                                                       fade).await {
+                return Err(HandlerError::from_error(
+                    e, "Failed to recall scene"));
+            }
+            let mut state = self.state.lock().unwrap();
+            let gs = state.group_mut(group);
+            gs.scene = Some((block, scene));
+            gs.level = None;
+            gs.proportion = None;
+            state.scene_block_mut(&HelvarAddress::Group(group), block)
+                .recall(scene);
+        }
+        match control.query_group_description(group).await {
+            Ok(descr) => {
+                self.state.lock().unwrap().group_mut(group).description = descr;
+            },
+            Err(e) => {
+                eprintln!("Failed to query description of group {}: {}",
+                          group, e);
+            }
+        }
+        let state = self.state.lock().unwrap();
+        Ok(match state.groups.get(&group) {
+            Some(gs) => group_to_json(gs, links),
+            None => group_to_json(&GroupState::new(group), links)
+        })
+    }
+}
+
+#[async_trait]
+impl RequestHandler for Handler 
+{
+    async fn handle(&mut self, req: &Request) -> Result<String, Box<dyn std::error::Error + Send>>
+    {
+        let links = Links::from_params(&req.params);
+        let mut router_arg = None::<(u8, u8)>;
+        let mut subnet_arg = None::<u8>;
+        let mut device_arg = None::<DeviceAddress>;
+        if let Some(path) = req.params.get("PATH_INFO") {
+            if path.trim_matches('/') == "workgroup" {
+                let workgroup = self.workgroup.lock().unwrap();
+                let obj = match workgroup.as_ref() {
+                    Some(w) => workgroup_to_json(w, &links),
+                    None => json::Value::Null
+                };
+                return Ok("Content-type: application/json\r\n\r\n".to_string()
+                          + &serde_json::to_string_pretty(&obj).unwrap());
+            }
+            if path.trim_matches('/') == "faults" {
+                let obj = faults_to_json(&self.state.lock().unwrap(), &links);
+                return Ok("Content-type: application/json\r\n\r\n".to_string()
+                          + &serde_json::to_string_pretty(&obj).unwrap());
+            }
+            if path.trim_matches('/') == "maintenance" {
+                let params = query_string::parse(
+                    req.params.get("QUERY_STRING").map_or("", |q| q.as_str()));
+                return self.maintenance(&params, &links)
+                    .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>);
+            }
+            if path.trim_matches('/') == "energy" {
+                let obj = energy_overview_to_json(&self.state.lock().unwrap(),
+                                                  &links);
+                return Ok("Content-type: application/json\r\n\r\n".to_string()
+                          + &serde_json::to_string_pretty(&obj).unwrap());
+            }
+            if path.trim_matches('/') == "emergency/report" {
+                let params = query_string::parse(
+                    req.params.get("QUERY_STRING").map_or("", |q| q.as_str()));
+                return self.emergency_report(&params)
+                    .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>);
+            }
+            if path.trim_matches('/') == "emergency" {
+                let obj = emergency_devices_to_json(
+                    &self.state.lock().unwrap(),
+                    &self.scheduler.lock().unwrap(), &links);
+                return Ok("Content-type: application/json\r\n\r\n".to_string()
+                          + &serde_json::to_string_pretty(&obj).unwrap());
+            }
+            // /cluster/router/subnet/device
+            let parts: Vec<&str> =
+                path.split('/').filter(|p| !p.is_empty()).collect();
+            if parts.first() == Some(&"groups") {
+                let params = query_string::parse(
+                    req.params.get("QUERY_STRING").map_or("", |q| q.as_str()));
+                let obj = self.handle_groups(&parts, &params, &links).await
+                    .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+                return Ok("Content-type: application/json\r\n\r\n".to_string()
+                          + &serde_json::to_string_pretty(&obj).unwrap());
+            }
+            if parts.len() > 4 && parts[4] != "scenes"
+                && !((parts[4] == "emergency" || parts[4] == "energy")
+                     && parts.len() == 5)
+            {
+                return Err(Box::new(HandlerError::new("Path too long")));
+            }
+            if parts.len() == 1 {
+                return Err(Box::new(HandlerError::new(
+                    "Both cluster and router must be given")));
+            }
+            if parts.len() == 3 && parts[2] == "clock" {
+                let params = query_string::parse(
+                    req.params.get("QUERY_STRING").map_or("", |q| q.as_str()));
+                let obj = match (address::parse_cluster(parts[0]),
+                                 address::parse_router(parts[1])) {
+                    (Ok(cluster), Ok(router)) =>
+                        self.handle_clock(cluster, router, &params,
+                                          &links).await,
+                    (Err(e), _) | (_, Err(e)) => Err(HandlerError::from_error(
+                        e, "Failed to parse router address"))
+                }.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+                return Ok("Content-type: application/json\r\n\r\n".to_string()
+                          + &serde_json::to_string_pretty(&obj).unwrap());
+            }
+            if parts.len() >= 2 {
+                let cluster = match address::parse_cluster(parts[0]) {
+                    Ok(c) => c,
+                    Err(e) => return Err(Box::new(HandlerError::from_error(
+                        e, "Failed to parse cluster")))
+                };
+                let router = match address::parse_router(parts[1]) {
+                    Ok(r) => r,
+                    Err(e) => return Err(Box::new(HandlerError::from_error(
+                        e, "Failed to parse router")))
+                };
+                router_arg = Some((cluster, router));
+            }
+            if let Some(subnet_str) = parts.get(2) {
+                subnet_arg = match address::parse_subnet(subnet_str)
+                {
+                    Ok(i) => Some(i),
+                    Err(e) => return Err(Box::new(HandlerError::from_error(
+                        e, "Failed to parse subnet index")))
+                };
+            }
+            if parts.len() >= 4 {
+                device_arg = match DeviceAddress::from_str(&parts[..4].join("."))
+                {
+                    Ok(a) => Some(a),
+                    Err(e) => return Err(Box::new(HandlerError::from_error(
+                        e,"Failed to parse address")))
+                };
+            }
+            if let (Some(address), true) = (device_arg, parts.len() > 4) {
+                let params = query_string::parse(
+                    req.params.get("QUERY_STRING").map_or("", |q| q.as_str()));
+                let target = HelvarAddress::Device(address);
+                let obj = match self.device_router(&address) {
+                    _ if parts[4] == "energy" =>
+                        self.handle_energy(&target, &params, &links),
+                    Ok(control) if parts[4] == "emergency" =>
+                        self.handle_emergency(&target, &params, &links,
+                                              &control).await,
+                    Ok(control) => self.handle_scenes(&target, &parts[5..],
+                                                      &params, &links,
+                                                      &control).await,
+                    Err(e) => Err(e)
+                }.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+                return Ok("Content-type: application/json\r\n\r\n".to_string()
+                          + &serde_json::to_string_pretty(&obj).unwrap());
+            }
+        }
+	
+
+        let params = query_string::parse(
+            req.params.get("QUERY_STRING").map_or("", |q| q.as_str()));
+        self.set_device_level(device_arg, &params).await
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+        let routers = self.routers.lock().unwrap();
+        let state = self.state.lock().unwrap();
+        let mut reply = "Content-type: application/json\r\n\r\n".to_string();
+
+        let top_obj = match router_arg {
+            Some((cluster, router)) => {
+                match state.get_router(cluster, router) {
+                    Some(rs) => match (subnet_arg, device_arg) {
+                        (Some(_), Some(address)) => {
+                            match state.get_device(&address) {
+                                Some(dev) => device_to_json(dev, &links),
+                                None => json::Value::Null
+                            }
+                        },
+                        (Some(subnet), None) => {
+                            match rs.get_subnet(subnet) {
+                                Some(sn) => subnet_to_json(sn, cluster, router,
+                                                           &links),
+                                None => json::Value::Null
+                            }
+                        },
+                        (None, _) => {
+                            let control = routers.routers.get(&(cluster, router));
+                            router_to_json(rs, cluster, router, control, &links)
+                        }
+                    },
+                    None => json::Value::Null
+                }
+            },
+            None => {
+                let mut router_map = serde_json::map::Map::new();
+                let mut router_links = Vec::new();
+                for (&(cluster, router), rs) in &state.routers {
+                    let control = routers.routers.get(&(cluster, router));
+                    router_map.insert(format!("{}.{}", cluster, router),
+                                      router_to_json(rs, cluster, router,
+                                                     control, &links));
+                    router_links.push(json!(links.router(cluster, router)));
+                }
+                json!({"routers": json!(router_map),
+                       "fastcgi": {
+                           "write_errors": self.fcgi_stats.write_errors()
+                       },
+                       "links": {
+                           "self": links.root(),
+                           "routers": router_links,
+                           "workgroup": links.workgroup(),
+                           "groups": links.groups(),
+                           "emergency": links.emergency_devices(),
+                           "faults": links.faults(),
+                           "energy": links.energy_overview(),
+                           "maintenance": links.maintenance()
+                       }})
+            }
+        };
+        reply += &serde_json::to_string_pretty(&top_obj).unwrap();
+        Ok(reply)
+    }
+}
+
+type StateArc = Arc<StdMutex<WorkgroupState>>;
+type WorkgroupArc = Arc<StdMutex<Option<Workgroup>>>;
+
+async fn connection_handler<S>(stream: Arc<Mutex<Box<S>>>, 
+                               mut ctxt: ConnectionContext)
+    where S: AsyncRead+AsyncWrite+Unpin+Send+'static
+{
+    let rec_stream = RecordInputStream::with_timeouts(stream.clone(),
+                                                      ctxt.timeouts);
+    let rec_output = RecordOutput::new(stream);
+    let mut decoder = Decoder::new();
+    decoder.set_stats(ctxt.handler.fcgi_stats.clone());
+    decoder.set_shutdown(ctxt.shutdown);
+    decoder.run(rec_stream,rec_output, 
+                &mut ctxt.handler).await;
+}
+
+async fn query_device(router: &Router, state: &StateArc,
+                      address: DeviceAddress, priority: u32,
+                      sensor_units: &SensorUnits)
+                      -> Result<(), Box<dyn std::error::Error>>
+{
+    let mut dev = {
+        let state = state.lock().unwrap();
+        state.get_device(&address).cloned()
+            .unwrap_or_else(|| DeviceState::new(address))
+    };
+    match router.query_device_type(&address).await {
+        Ok(dtype) => {
+            dev.device_type = dtype;
+        },
+        Err(HelvarError::NoSuchDevice) => return Ok(()),
+        Err(e) => {
+            return Err(WrapperError::new("Failed to query device type",
+                                         Box::new(e)).into());
+        }
+    }
+    if HelvarDeviceType::from(dev.device_type).is_load() { 
+        match router.query_load_level(&address).await {
+            Ok(level) => {
+                dev.intensity = u8::try_from(level).unwrap_or(0xff);
+            },
+            Err(HelvarError::NoSuchDevice) => return Ok(()),
+            Err(e) => {
+                return Err(WrapperError::new("Failed to query load level",
+                                             Box::new(e)).into());
+            }
+        }
+    }
+    if let Some(unit) = sensor_units.get(dev.device_type) {
+        match router.query_measurement(&address).await {
+            Ok(value) => {
+                dev.measurement = Some(Measurement{
+                    value, unit: unit.as_str(), updated: SystemTime::now()});
+            },
+            Err(HelvarError::NoSuchDevice) => return Ok(()),
+            // The rest of the device state is still worth updating
+            Err(e) => eprintln!("Failed to query measurement of device {}: {}",
+                                address, e)
+        }
+    }
+    // The state bitmask gives all faults at once, the fault queries
+    // confirm each one. A failed query keeps what the rest says, and
+    // without the bitmask the previous health is kept.
+    let mut flags = match router.query_device_state(&address).await {
+        Ok(flags) => Some(flags),
+        Err(HelvarError::NoSuchDevice) => return Ok(()),
+        Err(e) => {
+            eprintln!("Failed to query state of device {}: {}", address, e);
+            None
+        }
+    };
+    if let Some(state) = &mut flags {
+        for &fault in Fault::ALL.iter() {
+            match router.query_fault(&address, fault).await {
+                Ok(active) => *state = fault.apply(*state, active),
+                Err(HelvarError::NoSuchDevice) => return Ok(()),
+                Err(e) => eprintln!("Failed to query {} of device {}: {}",
+                                    fault.as_str(), address, e)
+            }
+        }
+    }
+    if priority <= 1 {
+        match router.query_device_description(&address).await {
+            Ok(descr) => {
+                dev.description = descr;
+            },
+            Err(e) => {
+                return Err(WrapperError::new("Failed to query device description",
+                                             Box::new(e)).into());
+            }
+        }
+    }
+    
+    let mut state = state.lock().unwrap();
+    match state.get_device_mut(&address) {
+        // Keep what other tasks have stored meanwhile
+        Some(old) => {
+            old.device_type = dev.device_type;
+            old.intensity = dev.intensity;
+            old.description = dev.description;
+            old.measurement = dev.measurement;
+            if let Some(flags) = flags {
+                old.health.update(flags);
+            }
+        },
+        None => {
+            if let Some(flags) = flags {
+                dev.health.update(flags);
+            }
+            state.set_device(dev)
+        }
+    }
+    
+    Ok(())
+}
+
+/// Wait until shutdown is requested
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>)
+{
+    while !*shutdown.borrow() {
+        if shutdown.recv().await.is_none() {
+            break;
+        }
+    }
+}
+
+/// The routers managed by the daemon
+#[derive(Default)]
+struct Routers
+{
+    routers: BTreeMap<(u8, u8), Router>,
+    // Poll tasks of the routers
+    tasks: Vec<JoinHandle<()>>
+}
+
+impl Routers
+{
+    /// A router to send workgroup wide commands to, preferably one
+    /// that is connected
+    fn any_router(&self) -> Option<Router>
+    {
+        self.routers.values()
+            .find(|r| r.connection_status().state == ConnectionState::Connected)
+            .or_else(|| self.routers.values().next())
+            .cloned()
+    }
+}
+
+type RoutersArc = Arc<StdMutex<Routers>>;
+
+/// How often data that changes slowly is polled. None disables polling.
+#[derive(Debug, Clone)]
+struct PollConfig
+{
+    /// Pause after querying a device in the device poll
+    device: Option<Duration>,
+    /// Units of the sensors read by the device poll
+    sensor_units: Arc<SensorUnits>,
+    emergency: Option<Duration>,
+    power: Option<Duration>,
+    running_hours: Option<Duration>,
+    clock_sync: Option<Duration>,
+    /// Drift in seconds from which the router clock is set
+    max_drift: i64,
+    /// False to only warn about drift and leave the router clock alone
+    set_clock: bool
+}
+
+/// Rated life of the device types and the fraction of it from which
+/// devices are listed for replacement
+#[derive(Debug)]
+struct MaintenanceConfig
+{
+    rated_life: RatedLifeTable,
+    threshold: f64
+}
+
+/// Start managing a router, with its own connection and poll task,
+/// unless it's already known
+fn add_router(routers: &RoutersArc, state: &StateArc,
+              conf: &RouterConfig, poll: &PollConfig,
+              shutdown: &watch::Receiver<bool>)
+{
+    let mut routers = routers.lock().unwrap();
+    let octets = conf.addr.octets();
+    let (cluster, router_index) = (octets[2], octets[3]);
+    if !address::CLUSTERS.contains(&cluster)
+        || !address::ROUTERS.contains(&router_index)
+    {
+        eprintln!("Ignoring router {}: The last two octets must be a valid \
+                   cluster and router number", conf.addr);
+        return;
+    }
+    if routers.routers.contains_key(&(cluster, router_index)) {
+        return;
+    }
+    let router = Router::new(&conf.addr, conf.transport);
+    // Make the router visible in the API before it's been polled
+    state.lock().unwrap().router_mut(cluster, router_index);
+    routers.tasks.push(tokio::spawn(router_poll_task(router.clone(),
+                                                     state.clone(),
+                                                     poll.device,
+                                                     poll.sensor_units.clone(),
+                                                     shutdown.clone())));
+    if let Some(interval) = poll.emergency {
+        routers.tasks.push(tokio::spawn(emergency_poll_task(router.clone(),
+                                                            state.clone(),
+                                                            interval,
+                                                            shutdown.clone())));
+    }
+    if let Some(interval) = poll.power {
+        routers.tasks.push(tokio::spawn(power_poll_task(router.clone(),
+                                                        state.clone(),
+                                                        interval,
+                                                        shutdown.clone())));
+    }
+    if let Some(interval) = poll.clock_sync {
+        routers.tasks.push(tokio::spawn(clock_sync_task(
+            router.clone(), state.clone(), interval, poll.max_drift,
+            poll.set_clock, shutdown.clone())));
+    }
+    if let Some(interval) = poll.running_hours {
+        routers.tasks.push(tokio::spawn(running_hours_poll_task(
+            router.clone(), state.clone(), interval, shutdown.clone())));
+    }
+    routers.routers.insert((cluster, router_index), router);
+}
+
+/// State shared by all FastCGI connections
+#[derive(Clone)]
+struct ConnectionContext
+{
+    handler: Handler,
+    timeouts: Timeouts,
+    shutdown: watch::Receiver<bool>,
+    // Every connection holds a sender, so the receiver sees the channel
+    // closed when all of them have finished
+    _running: mpsc::Sender<()>
+}
+
+async fn accept_task(mut listener: Listener, ctxt: ConnectionContext)
+{
+    let mut shutdown = ctxt.shutdown.clone();
+    loop {
+        let conn = tokio::select! {
+            conn = listener.accept() => conn,
+            _ = wait_for_shutdown(&mut shutdown) => break
+        };
+        match conn {
+            Ok(Connection::Unix(stream)) => {
+                let io = Arc::new(Mutex::new(Box::new(stream)));
+                tokio::spawn(connection_handler(io, ctxt.clone()));
+            },
+            Ok(Connection::Tcp(stream)) => {
+                let io = Arc::new(Mutex::new(Box::new(stream)));
+                tokio::spawn(connection_handler(io, ctxt.clone()));
+            },
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+            }
+        }
+    }
+}
+
+async fn fcgi_task(listeners: Vec<Listener>,
+                   handler: Handler,
+                   timeouts: Timeouts,
+                   mut shutdown: watch::Receiver<bool>,
+                   shutdown_timeout: Option<Duration>)
+{
+    let (running, mut all_done) = mpsc::channel::<()>(1);
+    let ctxt = ConnectionContext{
+        handler,
+        timeouts,
+        shutdown: shutdown.clone(),
+        _running: running
+    };
+    for listener in listeners {
+        tokio::spawn(accept_task(listener, ctxt.clone()));
+    }
+    drop(ctxt);
+    wait_for_shutdown(&mut shutdown).await;
+    match shutdown_timeout {
+        Some(timeout) => {
+            if tokio::time::timeout(timeout, all_done.recv()).await.is_err() {
+                eprintln!("Shutdown timeout with FastCGI requests still \
+                           in progress");
+            }
+        },
+        None => {
+            all_done.recv().await;
+        }
+    }
+}
+
+/// Listening sockets passed by systemd, or stdin as set up by a
+/// FastCGI process manager.
+fn fcgi_listeners() -> Result<Vec<Listener>, std::io::Error>
+{
+    let fds = systemd::listen_fds().unwrap_or_else(
+        || vec![FCGI_LISTENSOCK_FILENO as RawFd]);
+    fds.into_iter().map(Listener::from_raw_fd).collect()
+}
+
+async fn watchdog_task(interval: Duration,
+                       mut shutdown: watch::Receiver<bool>)
+{
+    loop {
+        if let Err(e) = systemd::notify("WATCHDOG=1") {
+            eprintln!("Failed to notify watchdog: {}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::delay_for(interval) => {},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+    }
+}
+
+/// Devices queried at the same time when polling a subnet, leaving
+/// room on the connection for the other pollers and API requests
+const SCAN_CONCURRENCY: usize = 4;
+
+/// Run futures concurrently within the calling task, so that dropping
+/// the returned future cancels all of them
+async fn join_all<F>(futures: Vec<F>)
+    where F: std::future::Future<Output = ()>
+{
+    let mut futures: Vec<_> = futures.into_iter()
+        .map(|f| Some(Box::pin(f)))
+        .collect();
+    std::future::poll_fn(|cx| {
+        let mut done = true;
+        for slot in futures.iter_mut() {
+            if let Some(f) = slot {
+                if f.as_mut().poll(cx).is_ready() {
+                    *slot = None;
+                } else {
+                    done = false;
+                }
+            }
+        }
+        if done {std::task::Poll::Ready(())} else {std::task::Poll::Pending}
+    }).await
+}
+
+/// Query all devices of a subnet. At most SCAN_CONCURRENCY devices are
+/// queried at a time, and each query is followed by a pause of
+/// `device_interval` before the next device is started.
+async fn scan_subnet(router: &Router, state: &StateArc,
+                     subnet: u8, priority: u32,
+                     device_interval: Option<Duration>,
+                     sensor_units: &SensorUnits)
+{
+    let permits = Semaphore::new(SCAN_CONCURRENCY);
+    let mut queries = Vec::new();
+    // Only DALI subnets are polled, and those have at most 64 devices
+    for a in 1..=64 {
+        let address = match router.device_address(subnet, a) {
+            Ok(address) => address,
+            Err(e) => {
+                eprintln!("Can't address devices on router {}: {}",
+                          router.address(), e);
+                return;
+            }
+        };
+        let permits = &permits;
+        queries.push(async move {
+            let _permit = permits.acquire().await;
+            if let Err(e) = query_device(router, state, address,
+                                         priority, sensor_units).await {
+                eprintln!("Query of device {} failed: {}", address, e);
+            }
+            if let Some(interval) = device_interval {
+                tokio::time::delay_for(interval).await;
+            }
+        });
+    }
+    join_all(queries).await;
+}
+
+async fn router_poll_task(router: Router, state: StateArc,
+                          device_interval: Option<Duration>,
+                          sensor_units: Arc<SensorUnits>,
+                          mut shutdown: watch::Receiver<bool>)
+{
+    loop {
+        tokio::select! {
+            connected = router.wait_connected() => if !connected {return},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+        for subnet in 1..=2 {
+            tokio::select! {
+                _ = scan_subnet(&router, &state, subnet, 0,
+                                device_interval, &sensor_units) => {},
+                _ = wait_for_shutdown(&mut shutdown) => return
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::delay_for(Duration::from_secs(1)) => {},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+    }
+}
+
+/// Query the emergency test state, battery and lamp time of an
+/// emergency luminaire
+async fn poll_emergency_device(router: &Router, state: &StateArc,
+                               address: DeviceAddress)
+                               -> Result<(), HelvarError>
+{
+    let flags = router.query_device_state(&address).await?;
+    let mut tests = Vec::new();
+    for &(kind, in_progress) in &[
+        (TestKind::Function, DeviceStateFlags::FUNCTION_TEST_IN_PROGRESS),
+        (TestKind::Duration, DeviceStateFlags::DURATION_TEST_IN_PROGRESS)]
+    {
+        let test_state = router.query_emergency_test_state(&address,
+                                                           kind).await?;
+        // The router has no time for a device that was never tested
+        let test_time = router.query_emergency_test_time(&address, kind)
+            .await.ok();
+        tests.push((kind, test_state, test_time, flags.contains(in_progress)));
+    }
+    let battery_charge = router.query_emergency_battery_charge(&address)
+        .await.ok();
+    let battery_failure = router.query_emergency_battery_failure(&address)
+        .await.ok();
+    let battery_time = router.query_emergency_battery_time(&address)
+        .await.ok();
+    let total_lamp_time = router.query_emergency_total_lamp_time(&address)
+        .await.ok();
+
+    let mut state = state.lock().unwrap();
+    if let Some(dev) = state.get_device_mut(&address) {
+        let em = dev.emergency.get_or_insert_with(EmergencyState::default);
+        for (kind, test_state, test_time, in_progress) in tests {
+            em.update(kind, test_state, test_time, in_progress);
+        }
+        em.battery_charge = battery_charge;
+        em.battery_failure = battery_failure;
+        em.battery_time = battery_time;
+        em.total_lamp_time = total_lamp_time;
+    }
+    Ok(())
+}
+
+/// Devices of a type found on a router so far
+fn router_devices<F>(state: &StateArc, cluster: u8, router: u8, filter: F)
+                     -> Vec<DeviceAddress>
+    where F: Fn(&HelvarDeviceType) -> bool
+{
+    let state = state.lock().unwrap();
+    let rs = match state.get_router(cluster, router) {
+        Some(rs) => rs,
+        None => return Vec::new()
+    };
+    rs.subnets.iter().filter_map(|sn| sn.as_ref())
+        .flat_map(|sn| sn.devices.iter().filter_map(|d| d.as_ref()))
+        .filter(|d| filter(&HelvarDeviceType::from(d.device_type)))
+        .map(|d| d.address)
+        .collect()
+}
+
+/// Run `poll` every `interval` while the router is connected. The
+/// first poll is done shortly after connecting, when the first scan of
+/// the subnets should have found the devices. A poll in progress is
+/// dropped on shutdown.
+async fn poll_router_periodically<F, P>(router: Router, interval: Duration,
+                                        mut shutdown: watch::Receiver<bool>,
+                                        mut poll: F)
+    where F: FnMut(Router) -> P,
+          P: Future<Output = ()>
+{
+    let mut delay = Duration::from_secs(10).min(interval);
+    loop {
+        tokio::select! {
+            connected = router.wait_connected() => if !connected {return},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+        tokio::select! {
+            _ = tokio::time::delay_for(delay) => {},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+        delay = interval;
+        tokio::select! {
+            _ = poll(router.clone()) => {},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+    }
+}
+
+/// Poll the emergency luminaires of a router every `interval`
+async fn emergency_poll_task(router: Router, state: StateArc,
+                             interval: Duration,
+                             shutdown: watch::Receiver<bool>)
+{
+    poll_router_periodically(router, interval, shutdown, |router| {
+        let state = state.clone();
+        async move {
+            let (cluster, router_index) = router.cluster_router();
+            for address in router_devices(&state, cluster, router_index,
+                                          HelvarDeviceType::is_emergency)
+            {
+                if let Err(e) = poll_emergency_device(&router, &state,
+                                                      address).await {
+                    eprintln!("Emergency poll of device {} failed: {}",
+                              address, e);
+                }
+            }
+        }
+    }).await
+}
+
+/// Add a power reading, creating the meter on the first one
+fn record_power(meter: &mut Option<EnergyMeter>, watts: f64)
+{
+    let now = SystemTime::now();
+    meter.get_or_insert_with(EnergyMeter::default)
+        .record(now, watts, dates::local_day(now));
+}
+
+/// Poll the power consumption of the loads of a router every
+/// `interval`
+async fn power_poll_task(router: Router, state: StateArc,
+                         interval: Duration,
+                         shutdown: watch::Receiver<bool>)
+{
+    poll_router_periodically(router, interval, shutdown, |router| {
+        let state = state.clone();
+        async move {
+            let (cluster, router_index) = router.cluster_router();
+            for address in router_devices(&state, cluster, router_index,
+                                          HelvarDeviceType::is_load)
+            {
+                match router.query_power_consumption(&address).await {
+                    Ok(watts) => {
+                        let mut state = state.lock().unwrap();
+                        if let Some(dev) = state.get_device_mut(&address) {
+                            record_power(&mut dev.power, watts);
+                        }
+                    },
+                    Err(e) => eprintln!("Power poll of device {} failed: {}",
+                                        address, e)
+                }
+            }
+        }
+    }).await
+}
+
+/// Poll the lamp and ballast running hours of the loads of a router
+/// every `interval`
+async fn running_hours_poll_task(router: Router, state: StateArc,
+                                 interval: Duration,
+                                 shutdown: watch::Receiver<bool>)
+{
+    poll_router_periodically(router, interval, shutdown, |router| {
+        let state = state.clone();
+        async move {
+            let (cluster, router_index) = router.cluster_router();
+            for address in router_devices(&state, cluster, router_index,
+                                          HelvarDeviceType::is_load)
+            {
+                // Not every ballast counts both, which the router
+                // reports as a property that doesn't exist
+                let counted = |res| match res {
+                    Ok(hours) => Ok(Some(hours)),
+                    Err(HelvarError::NoSuchProperty) => Ok(None),
+                    Err(e) => Err(e)
+                };
+                let hours = async {
+                    let lamp = router.query_lamp_running_hours(&address).await;
+                    let ballast = router.query_ballast_running_hours(&address)
+                        .await;
+                    Ok::<_, HelvarError>((counted(lamp)?, counted(ballast)?))
+                }.await;
+                let (lamp, ballast) = match hours {
+                    Ok((None, None)) => continue,
+                    Ok(hours) => hours,
+                    Err(e) => {
+                        eprintln!("Running hours poll of device {} failed: {}",
+                                  address, e);
+                        continue;
+                    }
+                };
+                let mut state = state.lock().unwrap();
+                if let Some(dev) = state.get_device_mut(&address) {
+                    dev.running_hours = Some(RunningHours{
+                        lamp, ballast, updated: Some(SystemTime::now())});
+                }
+            }
+        }
+    }).await
+}
+
+/// Compare the router clock with the host clock. If it has drifted by
+/// `max_drift` seconds or more it's set, or only reported unless
+/// `set_clock`.
+async fn sync_clock(router: &Router, max_drift: i64, set_clock: bool)
+                    -> Result<ClockCheck, HelvarError>
+{
+    let time = router.query_time().await?;
+    let checked = SystemTime::now();
+    let drift = time.drift(checked);
+    let drifted = drift.abs() >= max_drift;
+    if drifted {
+        eprintln!("Clock of router {} is {} s {} the host clock{}",
+                  router.address(), drift.abs(),
+                  if drift > 0 {"ahead of"} else {"behind"},
+                  if set_clock {", setting it"} else {""});
+    }
+    let synced = drifted && set_clock;
+    if synced {
+        router.set_time(RouterTime::from_system_time(SystemTime::now()))
+            .await?;
+    }
+    Ok(ClockCheck{drift, checked, synced})
+}
+
+/// Keep the router clock in step with the host clock, checking every
+/// `interval`
+async fn clock_sync_task(router: Router, state: StateArc,
+                         interval: Duration, max_drift: i64,
+                         set_clock: bool, shutdown: watch::Receiver<bool>)
+{
+    poll_router_periodically(router, interval, shutdown, |router| {
+        let state = state.clone();
+        async move {
+            match sync_clock(&router, max_drift, set_clock).await {
+                Ok(check) => {
+                    let (cluster, router_index) = router.cluster_router();
+                    state.lock().unwrap().router_mut(cluster, router_index)
+                        .clock = Some(check);
+                },
+                Err(e) => eprintln!("Clock check of router {} failed: {}",
+                                    router.address(), e)
+            }
+        }
+    }).await
+}
+
+/// Poll the power consumption of the known groups every `interval`
+async fn group_power_task(routers: RoutersArc, state: StateArc,
+                          interval: Duration,
+                          mut shutdown: watch::Receiver<bool>)
+{
+    loop {
+        tokio::select! {
+            _ = tokio::time::delay_for(interval) => {},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+        let router = match routers.lock().unwrap().any_router() {
+            Some(router) => router,
+            None => continue
+        };
+        let groups: Vec<u16> = state.lock().unwrap().groups.keys()
+            .copied().collect();
+        for group in groups {
+            let res = tokio::select! {
+                res = router.query_group_power_consumption(group) => res,
+                _ = wait_for_shutdown(&mut shutdown) => return
+            };
+            match res {
+                Ok(watts) => record_power(
+                    &mut state.lock().unwrap().group_mut(group).power, watts),
+                Err(e) => eprintln!("Power poll of group {} failed: {}",
+                                    group, e)
+            }
+        }
+    }
+}
+
+/// Drift in seconds from which a router clock is out of step, and
+/// whether it's then set or only reported
+fn clock_sync_config() -> Result<(i64, bool), String>
+{
+    let max_drift = match env::var("CLOCK_MAX_DRIFT") {
+        Ok(s) => match i64::from_str(&s) {
+            Ok(d) if d > 0 => d,
+            _ => return Err(format!("Invalid value for CLOCK_MAX_DRIFT: {}, \
+                                     expected seconds from 1", s))
+        },
+        Err(_) => 5
+    };
+    let set_clock = match env::var("CLOCK_SYNC_MODE") {
+        Ok(mode) => match mode.to_ascii_lowercase().as_str() {
+            "set" => true,
+            "warn" => false,
+            _ => return Err(format!("Invalid value for CLOCK_SYNC_MODE: {}, \
+                                     expected set or warn", mode))
+        },
+        Err(_) => true
+    };
+    Ok((max_drift, set_clock))
+}
+
+/// Power polling interval and the groups to poll from the start
+fn power_config() -> Result<(Option<Duration>, Vec<u16>), String>
+{
+    let interval = env_timeout("POWER_POLL_INTERVAL", 60)?;
+    if interval.is_some_and(|i| i > energy::MAX_GAP) {
+        return Err(format!("POWER_POLL_INTERVAL can't be more than {} s",
+                           energy::MAX_GAP.as_secs()));
+    }
+    let groups = match env::var("POWER_GROUPS") {
+        Ok(groups) => config::parse_group_list(&groups)?,
+        Err(_) => Vec::new()
+    };
+    Ok((interval, groups))
+}
+
+/// Measurement units of sensor types besides the built in ones
+fn sensor_units_config() -> Result<SensorUnits, String>
+{
+    match env::var("SENSOR_UNITS") {
+        Ok(units) => SensorUnits::from_str(&units)
+            .map_err(|e| format!("SENSOR_UNITS: {}", e)),
+        Err(_) => Ok(SensorUnits::default())
+    }
+}
+
+fn maintenance_config() -> Result<MaintenanceConfig, String>
+{
+    let rated_life = match env::var("RATED_LIFE") {
+        Ok(table) => RatedLifeTable::from_str(&table)?,
+        Err(_) => RatedLifeTable::default()
+    };
+    let threshold = match env::var("END_OF_LIFE_PERCENT") {
+        Ok(p) => match f64::from_str(&p) {
+            Ok(p) if (0.0..=100.0).contains(&p) => p / 100.0,
+            _ => return Err(format!("Invalid END_OF_LIFE_PERCENT \"{}\"", p))
+        },
+        Err(_) => 0.9
+    };
+    Ok(MaintenanceConfig{rated_life, threshold})
+}
+
+/// Find the routers in the workgroup of `router` every `interval`.
+/// Routers found are added to the managed ones.
+async fn discovery_task(router: Router, workgroup: WorkgroupArc,
+                        routers: RoutersArc, state: StateArc,
+                        poll: PollConfig, interval: Duration,
+                        mut shutdown: watch::Receiver<bool>)
+{
+    loop {
+        tokio::select! {
+            connected = router.wait_connected() => if !connected {return},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+        let delay = match discovery::discover(&router).await {
+            Ok(w) => {
+                for addr in w.router_addresses() {
+                    let conf = RouterConfig{addr,
+                                            transport: router.transport()};
+                    add_router(&routers, &state, &conf, &poll, &shutdown);
+                }
+                *workgroup.lock().unwrap() = Some(w);
+                interval
+            },
+            Err(e) => {
+                eprintln!("Router discovery failed: {}", e);
+                Duration::from_secs(60).min(interval)
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::delay_for(delay) => {},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+    }
+}
+
+/// Read a timeout in seconds from the environment. Zero disables the timeout.
+fn env_timeout(name: &str, default: u64) -> Result<Option<Duration>, String>
+{
+    let secs = match env::var(name) {
+        Ok(s) => match u64::from_str(&s) {
+            Ok(secs) => secs,
+            Err(e) => return Err(format!("Invalid value for {}: {}", name, e))
+        },
+        Err(_) => default
+    };
+    Ok(if secs == 0 {None} else {Some(Duration::from_secs(secs))})
+}
+
+/// Wait for SIGTERM or SIGINT
+async fn wait_for_signal() -> Result<(), std::io::Error>
+{
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = term.recv() => {},
+        _ = int.recv() => {}
+    }
+    Ok(())
+}
+
+/// Read the emergency test schedules from the environment.
+/// EMERGENCY_TEST_BATCHES is the number of batches the luminaires are
+/// split into by address. Groups aren't taken into account, see
+/// `schedule::stagger`.
+fn emergency_test_config() -> Result<TestScheduler, String>
+{
+    let schedule = |name: &str| match env::var(name) {
+        Ok(s) if !s.trim().is_empty() => Schedule::from_str(&s)
+            .map(|schedule| Some(ScheduledTest::new(schedule)))
+            .map_err(|e| format!("{}: {}", name, e)),
+        _ => Ok(None)
+    };
+    let batches = match env::var("EMERGENCY_TEST_BATCHES") {
+        Ok(s) => match usize::from_str(&s) {
+            Ok(b) if b > 0 => b,
+            _ => return Err(format!("Invalid value for \
+                                     EMERGENCY_TEST_BATCHES: {}", s))
+        },
+        Err(_) => 2
+    };
+    Ok(TestScheduler{
+        function: schedule("EMERGENCY_FUNCTION_TEST_SCHEDULE")?,
+        duration: schedule("EMERGENCY_DURATION_TEST_SCHEDULE")?,
+        batches
+    })
+}
+
+fn fcgi_timeouts() -> Result<Timeouts, String>
+{
+    Ok(Timeouts{
+        idle: env_timeout("FCGI_IDLE_TIMEOUT", 300)?,
+        header: env_timeout("FCGI_HEADER_TIMEOUT", 10)?,
+        body: env_timeout("FCGI_BODY_TIMEOUT", 30)?
+    })
+}
+
+#[tokio::main]
+async fn main() {
+    
+    let addr_str = match env::var("ROUTER_ADDRESS")
+    {
+        Ok(a) => a,
+        Err(_) => {
+            eprintln!("No environment variable ROUTER_ADDRESS found");
+            return;
+        }
+    };
+    let router_confs = match config::parse_router_list(&addr_str) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let timeouts = match fcgi_timeouts() {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    // Like the other timeouts, 0 waits for the requests without a limit
+    let shutdown_timeout = match env_timeout("SHUTDOWN_TIMEOUT", 10) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    // Discovery is disabled unless an interval is given
+    let discovery_interval = match env_timeout("DISCOVERY_INTERVAL", 0) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let device = match env_timeout("DEVICE_POLL_INTERVAL", 1) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let emergency = match env_timeout("EMERGENCY_POLL_INTERVAL", 300) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let (power, power_groups) = match power_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let running_hours = match env_timeout("RUNNING_HOURS_POLL_INTERVAL", 3600) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let maintenance = match maintenance_config() {
+        Ok(m) => Arc::new(m),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    // The router clocks are left alone unless an interval is given
+    let clock_sync = match env_timeout("CLOCK_SYNC_INTERVAL", 0) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let (max_drift, set_clock) = match clock_sync_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let sensor_units = match sensor_units_config() {
+        Ok(u) => Arc::new(u),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let poll = PollConfig{device, sensor_units, emergency, power,
+                          running_hours, clock_sync, max_drift, set_clock};
+    let scheduler = match emergency_test_config() {
+        Ok(s) => Arc::new(StdMutex::new(s)),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let listeners = match fcgi_listeners() {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to set up FastCGI listeners: {}", e);
+            return;
+        }
+    };
+    let state = Arc::new(StdMutex::new(WorkgroupState::new()));
+    let workgroup = Arc::new(StdMutex::new(None));
+    let routers = Arc::new(StdMutex::new(Routers::default()));
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    for group in power_groups {
+        state.lock().unwrap().group_mut(group);
+    }
+    // Starts in degraded mode if a router can't be reached yet
+    for conf in &router_confs {
+        add_router(&routers, &state, conf, &poll, &shutdown);
+    }
+    
+    let handler = Handler{state: state.clone(),
+                          routers: routers.clone(),
+                          workgroup: workgroup.clone(),
+                          scheduler: scheduler.clone(),
+                          maintenance,
+                          started: SystemTime::now(),
+                          fcgi_stats: Arc::new(DecoderStats::default())};
+    let fcgi = tokio::spawn(fcgi_task(listeners,
+                                      handler,
+                                      timeouts,
+                                      shutdown.clone(),
+                                      shutdown_timeout));
+    
+    if let Some(interval) = discovery_interval {
+        // The first router is the seed
+        let seed = routers.lock().unwrap().routers.values()
+            .find(|r| r.address() == router_confs[0].addr).cloned();
+        if let Some(seed) = seed {
+            tokio::spawn(discovery_task(seed, workgroup,
+                                        routers.clone(), state.clone(),
+                                        poll, interval, shutdown.clone()));
+        }
+    }
+    let scheduled = {
+        let scheduler = scheduler.lock().unwrap();
+        scheduler.function.is_some() || scheduler.duration.is_some()
+    };
+    if scheduled {
+        tokio::spawn(emergency_test_task(routers.clone(), state.clone(),
+                                         scheduler, shutdown.clone()));
+    }
+    if let Some(interval) = power {
+        routers.lock().unwrap().tasks.push(tokio::spawn(group_power_task(
+            routers.clone(), state.clone(), interval, shutdown.clone())));
+    }
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(watchdog_task(interval, shutdown));
+    }
+    if let Err(e) = systemd::notify("READY=1") {
+        eprintln!("Failed to notify service manager: {}", e);
+    }
+
+    if let Err(e) = wait_for_signal().await {
+        eprintln!("Failed to install signal handlers: {}", e);
+        return;
+    }
+    systemd::notify("STOPPING=1").unwrap_or(());
+    shutdown_tx.broadcast(true).unwrap_or(());
+    fcgi.await.unwrap();
+    let tasks: Vec<_> = routers.lock().unwrap().tasks.drain(..).collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    // Wait for any command in progress before closing the connections
+    let all: Vec<Router> =
+        routers.lock().unwrap().routers.values().cloned().collect();
+    for router in all {
+        if let Err(e) = router.close().await {
+            eprintln!("Failed to close connection to router {}: {}",
+                      router.address(), e);
+        }
+    }
 }
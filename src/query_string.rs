@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+
+/// Decode %XX escapes and '+' as used in form encoding. Malformed
+/// escapes are kept as they are.
+fn decode(s: &str) -> String
+{
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| char::from(b).to_digit(16);
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(h), Some(l)) => {
+                        decoded.push((h * 16 + l) as u8);
+                        i += 3;
+                        continue;
+                    },
+                    _ => decoded.push(b'%')
+                }
+            },
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b)
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Split a CGI QUERY_STRING into its parameters. Names and values are
+/// percent-decoded. Only the first value of a repeated parameter is
+/// kept.
+pub fn parse(query: &str) -> BTreeMap<String, String>
+{
+    let mut params = BTreeMap::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = match pair.find('=') {
+            Some(split) => (&pair[..split], &pair[split+1..]),
+            None => (pair, "")
+        };
+        params.entry(decode(name)).or_insert_with(|| decode(value));
+    }
+    params
+}
+
+#[test]
+fn test_parse_query()
+{
+    let params = parse("level=40&fade=100&&scene=1.2&level=3&flag");
+    assert_eq!(params.get("level").map(String::as_str), Some("40"));
+    assert_eq!(params.get("fade").map(String::as_str), Some("100"));
+    assert_eq!(params.get("scene").map(String::as_str), Some("1.2"));
+    assert_eq!(params.get("flag").map(String::as_str), Some(""));
+    assert_eq!(params.len(), 4);
+    assert!(parse("").is_empty());
+
+    let params = parse("from=2020-06-01&name=Hall%20A+1&bad=%2x%&%61ction=recall");
+    assert_eq!(params.get("name").map(String::as_str), Some("Hall A 1"));
+    assert_eq!(params.get("bad").map(String::as_str), Some("%2x%"));
+    assert_eq!(params.get("action").map(String::as_str), Some("recall"));
+}
@@ -0,0 +1,131 @@
+use helvar_cgi::helvarnet::address::DeviceAddress;
+use helvar_cgi::helvarnet::dali_state::WorkgroupState;
+use helvar_cgi::helvarnet::maintenance::{life_used, RatedLife, RatedLifeTable};
+use crate::report::csv_table;
+#[cfg(test)]
+use std::str::FromStr;
+
+/// A device with the lamp or ballast near the end of its rated life
+#[derive(Debug, Clone)]
+pub struct ReplacementRow
+{
+    pub address: DeviceAddress,
+    pub description: String,
+    pub device_type: u32,
+    pub lamp_hours: Option<u32>,
+    pub ballast_hours: Option<u32>,
+    pub rated: RatedLife,
+    /// Fraction of the rated life used
+    pub lamp_used: Option<f64>,
+    pub ballast_used: Option<f64>
+}
+
+impl ReplacementRow
+{
+    /// The larger of the lamp and ballast life used
+    pub fn used(&self) -> f64
+    {
+        self.lamp_used.unwrap_or(0.0).max(self.ballast_used.unwrap_or(0.0))
+    }
+
+    fn cells(&self) -> Vec<String>
+    {
+        let opt = |v: Option<u32>| v.map(|v| v.to_string()).unwrap_or_default();
+        let percent = |v: Option<f64>| v.map(|v| format!("{:.0}", v * 100.0))
+            .unwrap_or_default();
+        vec![self.address.to_string(), self.description.clone(),
+             format!("0x{:04x}", self.device_type),
+             opt(self.lamp_hours), opt(self.rated.lamp),
+             percent(self.lamp_used),
+             opt(self.ballast_hours), opt(self.rated.ballast),
+             percent(self.ballast_used)]
+    }
+}
+
+const COLUMNS: [&str; 9] = [
+    "Address", "Description", "Device type", "Lamp hours", "Lamp rated life",
+    "Lamp life used (%)", "Ballast hours", "Ballast rated life",
+    "Ballast life used (%)"];
+
+/// Devices to plan lamp or ballast replacement for
+pub struct ReplacementList
+{
+    /// Fraction of the rated life from which a device is listed
+    pub threshold: f64,
+    /// Most worn first
+    pub rows: Vec<ReplacementRow>
+}
+
+impl ReplacementList
+{
+    pub fn new(state: &WorkgroupState, table: &RatedLifeTable,
+               threshold: f64) -> ReplacementList
+    {
+        let mut rows = Vec::new();
+        for rs in state.routers.values() {
+            for sn in rs.subnets.iter().filter_map(|x| x.as_ref()) {
+                for dev in sn.devices.iter().filter_map(|x| x.as_ref()) {
+                    let (hours, rated) = match (&dev.running_hours,
+                                                table.get(dev.device_type)) {
+                        (Some(hours), Some(rated)) => (hours, rated),
+                        _ => continue
+                    };
+                    let row = ReplacementRow{
+                        address: dev.address,
+                        description: dev.description.clone(),
+                        device_type: dev.device_type,
+                        lamp_hours: hours.lamp,
+                        ballast_hours: hours.ballast,
+                        rated,
+                        lamp_used: life_used(hours.lamp, rated.lamp),
+                        ballast_used: life_used(hours.ballast, rated.ballast)
+                    };
+                    if row.used() >= threshold {
+                        rows.push(row);
+                    }
+                }
+            }
+        }
+        rows.sort_by(|a, b| b.used().total_cmp(&a.used()));
+        ReplacementList{threshold, rows}
+    }
+
+    pub fn to_csv(&self) -> String
+    {
+        csv_table(&COLUMNS, self.rows.iter().map(ReplacementRow::cells))
+    }
+}
+
+#[cfg(test)]
+use helvar_cgi::helvarnet::maintenance::RunningHours;
+#[cfg(test)]
+use crate::report::test_device;
+
+#[test]
+fn test_replacement_list()
+{
+    let mut state = WorkgroupState::new();
+    for &(device, lamp, ballast) in &[(3, Some(27000), Some(27000)),
+                                      (4, Some(1000), Some(95000)),
+                                      (5, Some(1000), None)] {
+        let mut dev = test_device(device, "Corridor");
+        dev.device_type = 0x0601;
+        dev.running_hours = Some(RunningHours{lamp, ballast, updated: None});
+        state.set_device(dev);
+    }
+
+    let table = RatedLifeTable::from_str("0x0601=30000/100000").unwrap();
+    let list = ReplacementList::new(&state, &table, 0.9);
+    assert_eq!(list.rows.len(), 2);
+    assert_eq!(list.rows[0].address.device, 4);
+    assert_eq!(list.rows[1].lamp_used, Some(0.9));
+
+    let csv = list.to_csv();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[2], "1.2.1.3,Corridor,0x0601,27000,30000,90,27000,100000,27");
+
+    // No rated life for the type
+    let list = ReplacementList::new(&state, &RatedLifeTable::default(), 0.0);
+    assert!(list.rows.is_empty());
+}
@@ -0,0 +1,320 @@
+use std::time::{Duration, SystemTime};
+use helvar_cgi::helvarnet::address::DeviceAddress;
+use helvar_cgi::helvarnet::dali_state::WorkgroupState;
+use helvar_cgi::helvarnet::emergency::{EmergencyState, TestKind, TestResult};
+use crate::dates::{format_date, format_time, parse_router_time};
+#[cfg(test)]
+use crate::dates::SECS_PER_DAY;
+
+/// Overall result of a luminaire in the report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compliance
+{
+    Pass,
+    Fail,
+    /// Not both tests done in the period
+    NotTested
+}
+
+impl Compliance
+{
+    pub fn as_str(&self) -> &'static str
+    {
+        match self {
+            Compliance::Pass => "PASS",
+            Compliance::Fail => "FAIL",
+            Compliance::NotTested => "NOT TESTED"
+        }
+    }
+}
+
+/// One emergency luminaire in the report
+#[derive(Debug, Clone)]
+pub struct ReportRow
+{
+    pub address: DeviceAddress,
+    pub description: String,
+    pub function_test: Option<TestResult>,
+    pub duration_test: Option<TestResult>,
+    pub battery_charge: Option<u32>,
+    pub battery_failure: Option<bool>,
+    pub compliance: Compliance
+}
+
+impl ReportRow
+{
+    fn new(address: DeviceAddress, description: &str, em: &EmergencyState,
+           from: Option<SystemTime>, to: Option<SystemTime>) -> ReportRow
+    {
+        // The time the router gives for the test, not when it was seen
+        let in_period = |r: &&TestResult| {
+            let time = r.test_time.as_deref().and_then(parse_router_time)
+                .unwrap_or(r.recorded);
+            from.is_none_or(|from| time >= from)
+                && to.is_none_or(|to| time < to)
+        };
+        let last = |kind| em.results.iter().rev()
+            .filter(|r| r.kind == kind).find(in_period).cloned();
+        let function_test = last(TestKind::Function);
+        let duration_test = last(TestKind::Duration);
+        let failed = [&function_test, &duration_test].iter()
+            .any(|t| t.as_ref().is_some_and(|t| t.state.failed()))
+            || em.battery_failure == Some(true);
+        let compliance = if failed {
+            Compliance::Fail
+        } else if function_test.is_some() && duration_test.is_some() {
+            Compliance::Pass
+        } else {
+            Compliance::NotTested
+        };
+        ReportRow{address, description: description.to_string(),
+                  function_test, duration_test,
+                  battery_charge: em.battery_charge,
+                  battery_failure: em.battery_failure,
+                  compliance}
+    }
+
+    fn battery(&self) -> String
+    {
+        match (self.battery_failure, self.battery_charge) {
+            (Some(true), _) => "FAILED".to_string(),
+            (_, Some(charge)) => format!("OK, {}% charged", charge),
+            (Some(false), None) => "OK".to_string(),
+            (None, None) => "Unknown".to_string()
+        }
+    }
+
+    fn cells(&self) -> Vec<String>
+    {
+        let test = |t: &Option<TestResult>| match t {
+            Some(t) => (t.test_time.clone()
+                        .unwrap_or_else(|| format_time(t.recorded)),
+                        t.state.as_str().to_string()),
+            None => (String::new(), "not tested".to_string())
+        };
+        let (function_time, function_result) = test(&self.function_test);
+        let (duration_time, duration_result) = test(&self.duration_test);
+        vec![self.address.to_string(), self.description.clone(),
+             function_time, function_result, duration_time, duration_result,
+             self.battery(), self.compliance.as_str().to_string()]
+    }
+}
+
+const COLUMNS: [&str; 8] = [
+    "Address", "Description", "Last function test", "Function test result",
+    "Last duration test", "Duration test result", "Battery", "Status"];
+
+/// Emergency lighting compliance report for a period. Test results
+/// aren't stored, so only the tests seen since the daemon was started,
+/// and the last tests the routers still report, can be included.
+pub struct Report
+{
+    pub from: Option<SystemTime>,
+    pub to: Option<SystemTime>,
+    /// When the daemon started collecting test results
+    pub started: SystemTime,
+    pub generated: SystemTime,
+    pub rows: Vec<ReportRow>
+}
+
+fn csv_field(s: &str) -> String
+{
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// A CSV file with a header line and one line per row
+pub fn csv_table<I>(columns: &[&str], rows: I) -> String
+    where I: IntoIterator<Item = Vec<String>>
+{
+    let mut csv = columns.join(",") + "\r\n";
+    for row in rows {
+        let cells: Vec<String> = row.iter().map(|c| csv_field(c)).collect();
+        csv += &cells.join(",");
+        csv += "\r\n";
+    }
+    csv
+}
+
+fn html_escape(s: &str) -> String
+{
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+impl Report
+{
+    /// Report on all emergency luminaires, using the latest tests done
+    /// from `from` up to, but not including, `to`
+    pub fn new(state: &WorkgroupState, started: SystemTime,
+               from: Option<SystemTime>, to: Option<SystemTime>) -> Report
+    {
+        let mut rows = Vec::new();
+        for rs in state.routers.values() {
+            for sn in rs.subnets.iter().filter_map(|x| x.as_ref()) {
+                for dev in sn.devices.iter().filter_map(|x| x.as_ref()) {
+                    if let Some(em) = &dev.emergency {
+                        rows.push(ReportRow::new(dev.address, &dev.description,
+                                                 em, from, to));
+                    }
+                }
+            }
+        }
+        Report{from, to, started, generated: SystemTime::now(), rows}
+    }
+
+    fn period(&self) -> String
+    {
+        match (self.from, self.to) {
+            (None, None) => "All recorded tests".to_string(),
+            // The end of the period isn't included
+            (from, to) => format!("{} to {}",
+                                  from.map(format_date).unwrap_or_default(),
+                                  to.map(|t| format_date(t - Duration::from_secs(1)))
+                                  .unwrap_or_default())
+        }
+    }
+
+    pub fn to_csv(&self) -> String
+    {
+        csv_table(&COLUMNS, self.rows.iter().map(ReportRow::cells))
+    }
+
+    /// A self-contained page meant to be printed
+    pub fn to_html(&self) -> String
+    {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Emergency lighting test report</title>\n<style>\n\
+             body {font-family: sans-serif; font-size: 10pt}\n\
+             table {border-collapse: collapse; width: 100%}\n\
+             th, td {border: 1px solid #000; padding: 2px 4px; text-align: left}\n\
+             tr {page-break-inside: avoid}\n\
+             .FAIL {font-weight: bold}\n\
+             </style>\n</head>\n<body>\n\
+             <h1>Emergency lighting test report</h1>\n");
+        html += &format!("<p>Period: {}<br>\nGenerated: {}<br>\n\
+                          Luminaires: {}, passed: {}, failed: {}, \
+                          not tested: {}</p>\n\
+                          <p>Only tests seen since {}, and the last tests \
+                          reported by the routers, are included.</p>\n",
+                         html_escape(&self.period()),
+                         format_time(self.generated),
+                         self.rows.len(),
+                         self.count(Compliance::Pass),
+                         self.count(Compliance::Fail),
+                         self.count(Compliance::NotTested),
+                         format_time(self.started));
+        html += "<table>\n<tr>";
+        for c in COLUMNS.iter() {
+            html += &format!("<th>{}</th>", c);
+        }
+        html += "</tr>\n";
+        for row in &self.rows {
+            html += &format!("<tr class=\"{}\">",
+                             row.compliance.as_str().replace(' ', "_"));
+            for c in row.cells() {
+                html += &format!("<td>{}</td>", html_escape(&c));
+            }
+            html += "</tr>\n";
+        }
+        html += "</table>\n<p>Signature:</p>\n</body>\n</html>\n";
+        html
+    }
+
+    pub fn count(&self, compliance: Compliance) -> usize
+    {
+        self.rows.iter().filter(|r| r.compliance == compliance).count()
+    }
+}
+
+#[cfg(test)]
+use helvar_cgi::helvarnet::dali_state::DeviceState;
+#[cfg(test)]
+use helvar_cgi::helvarnet::reply::EmergencyTestState;
+
+/// A device on router 1.2, subnet 1
+#[cfg(test)]
+pub fn test_device(device: u8, description: &str) -> DeviceState
+{
+    let mut dev = DeviceState::new(DeviceAddress::new(1, 2, 1, device)
+                                   .unwrap());
+    dev.description = description.to_string();
+    dev
+}
+
+#[test]
+fn test_report()
+{
+    let mut state = WorkgroupState::new();
+    let mut dev = test_device(3, "Exit, stairs \"B\"");
+    let mut em = EmergencyState::default();
+    em.update(TestKind::Function, EmergencyTestState(0),
+              Some("10:00:00 01-Jun-2020".to_string()), false);
+    em.update(TestKind::Duration, EmergencyTestState(0), None, true);
+    em.update(TestKind::Duration, EmergencyTestState(0), None, false);
+    em.battery_charge = Some(90);
+    dev.emergency = Some(em.clone());
+    state.set_device(dev.clone());
+
+    dev.address = DeviceAddress::new(1, 2, 1, 4).unwrap();
+    em.update(TestKind::Function, EmergencyTestState(0x02),
+              Some("10:00:00 01-Jul-2020".to_string()), false);
+    em.battery_failure = Some(true);
+    dev.emergency = Some(em);
+    state.set_device(dev.clone());
+
+    dev.address = DeviceAddress::new(1, 2, 1, 5).unwrap();
+    dev.emergency = Some(EmergencyState::default());
+    state.set_device(dev);
+
+    let report = Report::new(&state, SystemTime::now(), None, None);
+    assert_eq!(report.rows.len(), 3);
+    assert_eq!(report.rows[0].compliance, Compliance::Pass);
+    assert_eq!(report.rows[1].compliance, Compliance::Fail);
+    assert_eq!(report.rows[2].compliance, Compliance::NotTested);
+
+    let csv = report.to_csv();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].starts_with(
+        "1.2.1.3,\"Exit, stairs \"\"B\"\"\",10:00:00 01-Jun-2020,pass,"));
+    assert!(lines[1].ends_with(",\"OK, 90% charged\",PASS"));
+    assert!(lines[2].ends_with(",FAILED,FAIL"));
+    assert_eq!(csv_table(&["a", "b"], vec![vec!["1".to_string(),
+                                                 "x,y".to_string()]]),
+               "a,b\r\n1,\"x,y\"\r\n");
+
+    let html = report.to_html();
+    assert!(html.contains("Exit, stairs &quot;B&quot;"));
+    assert!(html.contains("passed: 1, failed: 1, not tested: 1"));
+
+    // Nothing done in the future
+    let from = SystemTime::now() + Duration::from_secs(SECS_PER_DAY);
+    let report = Report::new(&state, SystemTime::now(), Some(from), None);
+    assert_eq!(report.rows[0].compliance, Compliance::NotTested);
+
+    // Filtered by the router's test time. The duration test has no
+    // time, so it counts as done when it was seen.
+    let june = parse_router_time("00:00:00 01-Jun-2020");
+    let july = parse_router_time("00:00:00 01-Jul-2020");
+    let report = Report::new(&state, SystemTime::now(), june, july);
+    assert!(report.rows[0].function_test.is_some());
+    assert!(report.rows[0].duration_test.is_none());
+    assert!(report.rows[1].function_test.is_some());
+    assert_eq!(report.rows[1].function_test.as_ref().unwrap().test_time
+               .as_deref(), Some("10:00:00 01-Jun-2020"));
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+use crate::dates;
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// The days a schedule runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recurrence
+{
+    /// 0 for Monday
+    Weekly{weekday: u32},
+    /// Only days 1 to 28 so every month has the day
+    Monthly{day: u32},
+    Yearly{month: u32, day: u32}
+}
+
+/// A recurring time in the local time zone, e.g. "monthly 1 02:00",
+/// "weekly sun 03:30" or "yearly 03-15 02:00"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule
+{
+    pub recurrence: Recurrence,
+    pub hour: u32,
+    pub minute: u32
+}
+
+impl Schedule
+{
+    fn matches(&self, days: i64) -> bool
+    {
+        let (_, month, day) = dates::civil_from_days(days);
+        match self.recurrence {
+            Recurrence::Weekly{weekday} => dates::weekday(days) == weekday,
+            Recurrence::Monthly{day: d} => day == d,
+            Recurrence::Yearly{month: m, day: d} => month == m && day == d
+        }
+    }
+
+    /// The first time the schedule runs after `after`
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime>
+    {
+        let (year, month, day) = dates::local_date(after);
+        let today = dates::days_from_civil(year, month, day);
+        // February 29 may be four years away
+        for days in today..=today + 4 * 366 {
+            if !self.matches(days) {
+                continue;
+            }
+            let (year, month, day) = dates::civil_from_days(days);
+            if let Some(time) = dates::local_time(year, month, day,
+                                                  self.hour, self.minute) {
+                if time > after {
+                    return Some(time);
+                }
+            }
+        }
+        None
+    }
+}
+
+fn parse_number(s: &str, range: std::ops::RangeInclusive<u32>) -> Option<u32>
+{
+    u32::from_str(s).ok().filter(|v| range.contains(v))
+}
+
+impl FromStr for Schedule
+{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let err = || format!("Invalid schedule \"{}\", expected e.g. \
+                              \"weekly sun 02:00\", \"monthly 1 02:00\" \
+                              or \"yearly 03-15 02:00\"", s);
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err(err());
+        }
+        let recurrence = match parts[0].to_ascii_lowercase().as_str() {
+            "weekly" => {
+                let day = parts[1].to_ascii_lowercase();
+                match WEEKDAYS.iter().position(|&d| d == day) {
+                    Some(weekday) => Recurrence::Weekly{weekday: weekday as u32},
+                    None => return Err(err())
+                }
+            },
+            "monthly" => match parse_number(parts[1], 1..=28) {
+                Some(day) => Recurrence::Monthly{day},
+                None => return Err(err())
+            },
+            "yearly" => {
+                let mut date = parts[1].splitn(2, '-');
+                let month = date.next().and_then(|m| parse_number(m, 1..=12));
+                let day = date.next().and_then(|d| parse_number(d, 1..=31));
+                match (month, day) {
+                    // Checked against a leap year so February 29 is allowed
+                    (Some(month), Some(day))
+                        if dates::civil_from_days(dates::days_from_civil(
+                            2000, month, day)) == (2000, month, day) =>
+                        Recurrence::Yearly{month, day},
+                    _ => return Err(err())
+                }
+            },
+            _ => return Err(err())
+        };
+        let mut time = parts[2].splitn(2, ':');
+        let hour = time.next().and_then(|h| parse_number(h, 0..=23));
+        let minute = time.next().and_then(|m| parse_number(m, 0..=59));
+        match (hour, minute) {
+            (Some(hour), Some(minute)) => Ok(Schedule{recurrence, hour, minute}),
+            _ => Err(err())
+        }
+    }
+}
+
+impl fmt::Display for Schedule
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.recurrence {
+            Recurrence::Weekly{weekday} =>
+                write!(f, "weekly {}", WEEKDAYS[weekday as usize])?,
+            Recurrence::Monthly{day} => write!(f, "monthly {}", day)?,
+            Recurrence::Yearly{month, day} =>
+                write!(f, "yearly {:02}-{:02}", month, day)?
+        }
+        write!(f, " {:02}:{:02}", self.hour, self.minute)
+    }
+}
+
+/// Split items into `count` batches so neighbours end up in different
+/// batches. With the items sorted by address, adjacent luminaires are
+/// never tested at the same time. Only addresses count: the daemon
+/// doesn't know which groups a device is in, so two luminaires of the
+/// same group far apart in address can still be tested together.
+pub fn stagger<T: Clone>(items: &[T], count: usize) -> Vec<Vec<T>>
+{
+    let count = count.max(1);
+    let mut batches = vec![Vec::new(); count];
+    for (i, item) in items.iter().enumerate() {
+        batches[i % count].push(item.clone());
+    }
+    batches.retain(|b| !b.is_empty());
+    batches
+}
+
+#[test]
+fn test_schedule_parse()
+{
+    let s = Schedule::from_str("monthly 1 02:00").unwrap();
+    assert_eq!(s.recurrence, Recurrence::Monthly{day: 1});
+    assert_eq!((s.hour, s.minute), (2, 0));
+    assert_eq!(s.to_string(), "monthly 1 02:00");
+    let s = Schedule::from_str("Weekly SUN 23:59").unwrap();
+    assert_eq!(s.recurrence, Recurrence::Weekly{weekday: 6});
+    assert_eq!(s.to_string(), "weekly sun 23:59");
+    let s = Schedule::from_str("yearly 3-15 2:30").unwrap();
+    assert_eq!(s.to_string(), "yearly 03-15 02:30");
+    assert!(Schedule::from_str("monthly 31 02:00").is_err());
+    assert!(Schedule::from_str("daily 02:00").is_err());
+    assert!(Schedule::from_str("weekly mon 24:00").is_err());
+    assert!(Schedule::from_str("yearly 13-01 02:00").is_err());
+    assert!(Schedule::from_str("yearly 02-30 02:00").is_err());
+    assert!(Schedule::from_str("yearly 04-31 02:00").is_err());
+    assert!(Schedule::from_str("yearly 02-29 02:00").is_ok());
+}
+
+#[test]
+fn test_schedule_next()
+{
+    let now = SystemTime::now();
+    let s = Schedule::from_str("weekly wed 02:00").unwrap();
+    let next = s.next_after(now).unwrap();
+    assert!(next > now);
+    assert!(next.duration_since(now).unwrap().as_secs() <= 8 * 24 * 3600);
+    let (year, month, day) = dates::local_date(next);
+    assert_eq!(dates::weekday(dates::days_from_civil(year, month, day)), 2);
+    // The following one is a week later
+    let after = s.next_after(next).unwrap();
+    let hours = after.duration_since(next).unwrap().as_secs() / 3600;
+    assert!((167..=169).contains(&hours));
+
+    let s = Schedule::from_str("yearly 02-29 12:00").unwrap();
+    let next = s.next_after(now).unwrap();
+    let (_, month, day) = dates::local_date(next);
+    assert_eq!((month, day), (2, 29));
+}
+
+#[test]
+fn test_stagger()
+{
+    let batches = stagger(&[1, 2, 3, 4, 5], 2);
+    assert_eq!(batches, vec![vec![1, 3, 5], vec![2, 4]]);
+    assert_eq!(stagger(&[1], 3), vec![vec![1]]);
+    assert!(stagger::<u8>(&[], 2).is_empty());
+}
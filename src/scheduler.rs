@@ -0,0 +1,225 @@
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use helvar_cgi::helvarnet::address::{DeviceAddress, HelvarAddress};
+use helvar_cgi::helvarnet::device_type::HelvarDeviceType;
+use helvar_cgi::helvarnet::emergency::{EmergencyAction, EmergencyState,
+                                       TestKind};
+use helvar_cgi::helvarnet::error::HelvarError;
+use helvar_cgi::helvarnet::router::Router;
+use crate::schedule::{stagger, Schedule};
+use crate::{wait_for_shutdown, RoutersArc, StateArc};
+
+/// Progress of the scheduled tests of one kind
+#[derive(Debug, Clone)]
+pub struct ScheduledTest
+{
+    pub schedule: Schedule,
+    pub next: Option<SystemTime>,
+    pub started: Option<SystemTime>,
+    pub finished: Option<SystemTime>,
+    /// The batch being tested and the number of batches
+    pub batch: Option<(usize, usize)>,
+    pub tested: usize,
+    /// Devices skipped in the last run as a test was already running
+    pub skipped: usize
+}
+
+impl ScheduledTest
+{
+    pub fn new(schedule: Schedule) -> ScheduledTest
+    {
+        ScheduledTest{schedule, next: None, started: None, finished: None,
+                      batch: None, tested: 0, skipped: 0}
+    }
+}
+
+/// Runs emergency tests on a calendar. The luminaires are split into
+/// batches so only some of them have drained batteries at a time.
+/// Single devices are tested, never whole groups.
+#[derive(Debug, Clone)]
+pub struct TestScheduler
+{
+    pub function: Option<ScheduledTest>,
+    pub duration: Option<ScheduledTest>,
+    pub batches: usize
+}
+
+impl TestScheduler
+{
+    fn test_mut(&mut self, kind: TestKind) -> Option<&mut ScheduledTest>
+    {
+        match kind {
+            TestKind::Function => self.function.as_mut(),
+            TestKind::Duration => self.duration.as_mut()
+        }
+    }
+}
+
+pub type SchedulerArc = Arc<StdMutex<TestScheduler>>;
+
+/// Longest time a batch is waited for before the next one is started
+fn max_test_time(kind: TestKind) -> Duration
+{
+    match kind {
+        TestKind::Function => Duration::from_secs(30 * 60),
+        // Batteries are rated for up to three hours
+        TestKind::Duration => Duration::from_secs(4 * 60 * 60)
+    }
+}
+
+/// Start a test unless one is already requested or running.
+/// Returns true if the test was started.
+async fn start_scheduled_test(router: &Router, state: &StateArc,
+                              address: DeviceAddress, kind: TestKind)
+                              -> Result<bool, HelvarError>
+{
+    let flags = router.query_device_state(&address).await?;
+    let active = TestKind::Function.active_flags()
+        | TestKind::Duration.active_flags();
+    if flags.0 & active != 0 {
+        return Ok(false);
+    }
+    router.emergency_action(&HelvarAddress::Device(address),
+                            EmergencyAction::Start(kind)).await?;
+    let mut state = state.lock().unwrap();
+    if let Some(dev) = state.get_device_mut(&address) {
+        dev.emergency.get_or_insert_with(EmergencyState::default)
+            .test_mut(kind).requested = Some(SystemTime::now());
+    }
+    Ok(true)
+}
+
+/// Wait until the tests of a batch are done or have taken too long
+async fn wait_for_batch(routers: &RoutersArc, batch: &[DeviceAddress],
+                        kind: TestKind)
+{
+    let deadline = tokio::time::Instant::now() + max_test_time(kind);
+    let mut waiting = batch.to_vec();
+    while !waiting.is_empty() {
+        if tokio::time::Instant::now() >= deadline {
+            eprintln!("Scheduled {} tests still running on {} devices",
+                      kind, waiting.len());
+            return;
+        }
+        tokio::time::delay_for(Duration::from_secs(30)).await;
+        let mut still_running = Vec::new();
+        for address in waiting {
+            let router = routers.lock().unwrap().routers
+                .get(&(address.cluster, address.router)).cloned();
+            let running = match router {
+                Some(router) => match router.query_device_state(&address).await {
+                    Ok(flags) => flags.0 & kind.active_flags() != 0,
+                    // Try again later
+                    Err(_) => true
+                },
+                None => false
+            };
+            if running {
+                still_running.push(address);
+            }
+        }
+        waiting = still_running;
+    }
+}
+
+/// Test all known emergency luminaires, one batch at a time
+async fn run_scheduled_tests(routers: &RoutersArc, state: &StateArc,
+                             scheduler: &SchedulerArc, kind: TestKind)
+{
+    let devices: Vec<DeviceAddress> = {
+        let state = state.lock().unwrap();
+        state.routers.values()
+            .flat_map(|rs| rs.subnets.iter().filter_map(|sn| sn.as_ref()))
+            .flat_map(|sn| sn.devices.iter().filter_map(|d| d.as_ref()))
+            .filter(|d| HelvarDeviceType::from(d.device_type).is_emergency())
+            .map(|d| d.address)
+            .collect()
+    };
+    let batch_count = scheduler.lock().unwrap().batches;
+    let batches = stagger(&devices, batch_count);
+    if let Some(test) = scheduler.lock().unwrap().test_mut(kind) {
+        test.started = Some(SystemTime::now());
+        test.finished = None;
+        test.tested = 0;
+        test.skipped = 0;
+    }
+    for (index, batch) in batches.iter().enumerate() {
+        let mut started = Vec::new();
+        let mut skipped = 0;
+        for &address in batch {
+            let router = routers.lock().unwrap().routers
+                .get(&(address.cluster, address.router)).cloned();
+            let res = match router {
+                Some(router) => start_scheduled_test(&router, state,
+                                                     address, kind).await,
+                None => Ok(false)
+            };
+            match res {
+                Ok(true) => started.push(address),
+                Ok(false) => skipped += 1,
+                Err(e) => {
+                    eprintln!("Failed to start {} test of device {}: {}",
+                              kind, address, e);
+                    skipped += 1;
+                }
+            }
+        }
+        if let Some(test) = scheduler.lock().unwrap().test_mut(kind) {
+            test.batch = Some((index + 1, batches.len()));
+            test.tested += started.len();
+            test.skipped += skipped;
+        }
+        wait_for_batch(routers, &started, kind).await;
+    }
+    if let Some(test) = scheduler.lock().unwrap().test_mut(kind) {
+        test.batch = None;
+        test.finished = Some(SystemTime::now());
+    }
+}
+
+/// Run the scheduled emergency tests when they are due
+pub(crate) async fn emergency_test_task(routers: RoutersArc, state: StateArc,
+                                        scheduler: SchedulerArc,
+                                        mut shutdown: watch::Receiver<bool>)
+{
+    {
+        let now = SystemTime::now();
+        let scheduler = &mut *scheduler.lock().unwrap();
+        for test in scheduler.function.iter_mut()
+            .chain(scheduler.duration.iter_mut())
+        {
+            test.next = test.schedule.next_after(now);
+        }
+    }
+    loop {
+        // The function test goes first if both are due at once
+        let due = {
+            let scheduler = scheduler.lock().unwrap();
+            let tests = [(TestKind::Function, &scheduler.function),
+                         (TestKind::Duration, &scheduler.duration)];
+            tests.iter()
+                .filter_map(|(kind, t)| Some((*kind, t.as_ref()?.next?)))
+                .min_by_key(|&(_, next)| next)
+        };
+        let (kind, time) = match due {
+            Some(due) => due,
+            None => return
+        };
+        // Sleep in steps so changes of the system clock are noticed
+        while let Ok(wait) = time.duration_since(SystemTime::now()) {
+            tokio::select! {
+                _ = tokio::time::delay_for(wait.min(Duration::from_secs(3600))) => {},
+                _ = wait_for_shutdown(&mut shutdown) => return
+            }
+        }
+        tokio::select! {
+            _ = run_scheduled_tests(&routers, &state, &scheduler, kind) => {},
+            _ = wait_for_shutdown(&mut shutdown) => return
+        }
+        if let Some(test) = scheduler.lock().unwrap().test_mut(kind) {
+            test.next = test.schedule.next_after(time);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::env;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// First file descriptor passed by systemd
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Check if the variable `pid_var` names this process
+fn for_this_process(pid_var: &str) -> bool
+{
+    match env::var(pid_var) {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        Err(_) => false
+    }
+}
+
+/// Returns the file descriptors passed through socket activation.
+/// The environment variables are removed so they are not inherited by
+/// child processes. Returns None if the process wasn't socket activated
+/// or no descriptors were passed.
+pub fn listen_fds() -> Option<Vec<RawFd>>
+{
+    if !for_this_process("LISTEN_PID") {
+        return None;
+    }
+    let count = env::var("LISTEN_FDS").ok()?.parse::<RawFd>().ok()?;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if count < 1 {
+        return None;
+    }
+    let fds: Vec<RawFd> =
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect();
+    for &fd in &fds {
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags >= 0 {
+                libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+            }
+        }
+    }
+    Some(fds)
+}
+
+/// Send a state string like "READY=1" to the service manager.
+/// Does nothing if NOTIFY_SOCKET isn't set.
+pub fn notify(state: &str) -> Result<(), io::Error>
+{
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(p) => p,
+        Err(_) => return Ok(())
+    };
+    let socket = UnixDatagram::unbound()?;
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+    } else {
+        socket.send_to(state.as_bytes(), &path)?;
+    }
+    Ok(())
+}
+
+/// Returns the interval for sending "WATCHDOG=1", which is half of the
+/// timeout requested by the service manager.
+pub fn watchdog_interval() -> Option<Duration>
+{
+    if env::var("WATCHDOG_PID").is_ok() && !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[test]
+fn test_listen_fds_none_passed()
+{
+    for count in &["0", "-2"] {
+        env::set_var("LISTEN_PID", std::process::id().to_string());
+        env::set_var("LISTEN_FDS", count);
+        assert_eq!(listen_fds(), None);
+        assert!(env::var("LISTEN_FDS").is_err());
+    }
+}